@@ -0,0 +1,43 @@
+/// Residual offsets in seconds below which a bias is considered noise.
+const BIAS_THRESHOLD: f64 = 0.005;
+
+/// Estimates the bias of the server the clock was just set from,
+/// given the offsets the other servers measured right afterwards.
+///
+/// If path delays are asymmetric the offset computed from the chosen server
+/// is off by half the asymmetry. The other servers then agree with each other
+/// but report a common offset relative to the new clock. Returns that offset
+/// if it is significant and the servers are consistent about it.
+pub fn estimate_bias(offsets: &[f64]) -> Option<f64> {
+    if offsets.len() < 2 {
+        return None;
+    }
+
+    let median = median(offsets);
+    let deviations: Vec<f64> = offsets
+        .iter()
+        .map(|offset| (offset - median).abs())
+        .collect();
+    let spread = median_of(deviations);
+
+    if median.abs() > BIAS_THRESHOLD && spread < median.abs() / 2.0 {
+        Some(median)
+    } else {
+        None
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    median_of(values.to_vec())
+}
+
+fn median_of(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
@@ -0,0 +1,263 @@
+use crate::packet::PACKET_LEN;
+use crate::{Error, Result};
+
+use std::path::Path;
+
+use tokio::fs;
+
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Algorithm {
+    Md5,
+    Sha1,
+}
+
+impl Algorithm {
+    fn digest_len(&self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+        }
+    }
+}
+
+/// A symmetric key as defined in an ntpd style keys file.
+#[derive(Clone, Debug)]
+pub struct Key {
+    id: u32,
+    algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+impl Key {
+    /// Loads the key with the specified id from a keys file.
+    /// Each line consists of the key id, the algorithm (MD5 or SHA1)
+    /// and the key itself, either as ASCII or as 40 hex digits.
+    pub async fn load(path: &Path, id: u32) -> Result<Self> {
+        let keys = fs::read_to_string(path).await?;
+
+        for line in keys.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(Error::MalformedKey(line.to_string()));
+            }
+
+            if fields[0].parse::<u32>()? != id {
+                continue;
+            }
+
+            let algorithm = match fields[1].to_ascii_uppercase().as_str() {
+                "MD5" | "M" => Algorithm::Md5,
+                "SHA1" => Algorithm::Sha1,
+                _ => return Err(Error::UnsupportedAlgorithm(fields[1].to_string())),
+            };
+
+            let secret = if fields[2].len() == 40 {
+                decode_hex(fields[2]).ok_or_else(|| Error::MalformedKey(line.to_string()))?
+            } else {
+                fields[2].as_bytes().to_vec()
+            };
+
+            return Ok(Self {
+                id,
+                algorithm,
+                secret,
+            });
+        }
+
+        Err(Error::KeyNotFound(id))
+    }
+
+    /// Appends the key identifier and message digest to an outgoing packet.
+    pub fn sign(&self, packet: &mut Vec<u8>) {
+        let digest = self.digest(packet);
+
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&digest);
+    }
+
+    /// Verifies the key identifier and message digest trailing a received packet.
+    pub fn verify(&self, packet: &[u8]) -> Result<()> {
+        let mac_len = 4 + self.algorithm.digest_len();
+        if packet.len() < PACKET_LEN + mac_len {
+            return Err(Error::Unauthenticated);
+        }
+
+        let (data, mac) = packet.split_at(packet.len() - mac_len);
+        let (key_id, digest) = mac.split_at(4);
+
+        // A comparison that stops at the first wrong byte would let
+        // an attacker guess a valid digest byte by byte.
+        let authentic = digest.ct_eq(&self.digest(data));
+        if key_id != self.id.to_be_bytes() || !bool::from(authentic) {
+            return Err(Error::BadMac);
+        }
+
+        Ok(())
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            Algorithm::Md5 => {
+                let mut hasher = Md5::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+            Algorithm::Sha1 => {
+                let mut hasher = Sha1::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::packet::{Packet, Timestamp};
+
+    const HEX: &str = "00112233445566778899aabbccddeeff00112233";
+
+    /// Writes a keys file to a file unique to the test.
+    fn keys(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rsdsl_ntp-{}-{}.keys", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    async fn load(name: &str, contents: &str, id: u32) -> Result<Key> {
+        let path = keys(name, contents);
+        let key = Key::load(&path, id).await;
+        std::fs::remove_file(&path).unwrap();
+
+        key
+    }
+
+    fn packet() -> Vec<u8> {
+        Packet::client(Timestamp {
+            sec: 3_900_000_000,
+            frac: 0,
+        })
+        .encode()
+        .to_vec()
+    }
+
+    #[tokio::test]
+    async fn load_keys() {
+        let contents = format!(
+            "# ntpd style keys\n\
+            \n\
+            1 MD5 secret # ascii\n\
+            2 sha1 {}\n\
+            3 M short\n",
+            HEX
+        );
+
+        let md5 = load("load", &contents, 1).await.unwrap();
+        assert_eq!(md5.algorithm, Algorithm::Md5);
+        assert_eq!(md5.secret, b"secret");
+
+        let sha1 = load("load", &contents, 2).await.unwrap();
+        assert_eq!(sha1.algorithm, Algorithm::Sha1);
+        assert_eq!(sha1.secret, decode_hex(HEX).unwrap());
+        assert_eq!(sha1.secret.len(), 20);
+
+        let short = load("load", &contents, 3).await.unwrap();
+        assert_eq!(short.algorithm, Algorithm::Md5);
+
+        assert!(matches!(
+            load("load", &contents, 4).await,
+            Err(Error::KeyNotFound(4))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_malformed_keys() {
+        assert!(matches!(
+            load("bad-id", "x MD5 secret\n", 1).await,
+            Err(Error::ParseInt(_))
+        ));
+        assert!(matches!(
+            load("algorithm", "1 SHA256 secret\n", 1).await,
+            Err(Error::UnsupportedAlgorithm(algorithm)) if algorithm == "SHA256"
+        ));
+        assert!(matches!(
+            load("fields", "1 MD5\n", 1).await,
+            Err(Error::MalformedKey(_))
+        ));
+        assert!(matches!(
+            load("fields", "1 MD5 secret trailing\n", 1).await,
+            Err(Error::MalformedKey(_))
+        ));
+
+        let not_hex = "g".repeat(40);
+        assert!(matches!(
+            load("hex", &format!("1 SHA1 {}\n", not_hex), 1).await,
+            Err(Error::MalformedKey(_))
+        ));
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        for algorithm in [Algorithm::Md5, Algorithm::Sha1] {
+            let key = Key {
+                id: 7,
+                algorithm,
+                secret: b"secret".to_vec(),
+            };
+
+            let mut packet = packet();
+            key.sign(&mut packet);
+            assert_eq!(packet.len(), PACKET_LEN + 4 + algorithm.digest_len());
+            key.verify(&packet).unwrap();
+
+            let mut flipped = packet.clone();
+            *flipped.last_mut().unwrap() ^= 1;
+            assert!(matches!(key.verify(&flipped), Err(Error::BadMac)));
+
+            let mut tampered = packet.clone();
+            tampered[0] ^= 1;
+            assert!(matches!(key.verify(&tampered), Err(Error::BadMac)));
+
+            let other = Key {
+                id: 8,
+                ..key.clone()
+            };
+            assert!(matches!(other.verify(&packet), Err(Error::BadMac)));
+
+            let secret = Key {
+                secret: b"other".to_vec(),
+                ..key.clone()
+            };
+            assert!(matches!(secret.verify(&packet), Err(Error::BadMac)));
+
+            assert!(matches!(
+                key.verify(&packet[..PACKET_LEN]),
+                Err(Error::Unauthenticated)
+            ));
+        }
+    }
+}
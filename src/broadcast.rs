@@ -0,0 +1,185 @@
+use crate::auth::Key;
+use crate::client::Sample;
+use crate::config::Config;
+use crate::packet::{Packet, Timestamp, MODE_BROADCAST};
+use crate::{Result, NTP_PORT};
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Instant;
+
+use tokio::net::UdpSocket;
+
+/// Pseudo server name standing for broadcast and multicast servers
+/// in the server order, health statistics and status.
+pub const SERVER: &str = "broadcast";
+
+/// The multicast group NTP servers announce the time to (RFC 5905).
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 1);
+
+/// Listens for the next broadcast or multicast announcement of a
+/// synchronized server. Its MAC has to be valid if a key is specified.
+/// There's no way to measure the delay, `broadcast_delay` is assumed instead.
+pub async fn sample(config: &Config, key: Option<&Key>) -> Result<Sample> {
+    // Announcements are sent to port 123 and don't reach a socket
+    // bound to a unicast address, `bind` only selects the interface.
+    let local = match config.bind.map(|bind| bind.ip()) {
+        Some(IpAddr::V4(ip_addr)) => ip_addr,
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, NTP_PORT)).await?;
+    if let Some(interface) = &config.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    socket.join_multicast_v4(MULTICAST_GROUP, local)?;
+
+    let mut buf = [0; 2048];
+    loop {
+        let (n, source) = socket.recv_from(&mut buf).await?;
+        let received = Instant::now();
+        let t4 = Timestamp::now()?;
+
+        let Some((packet, reference_age)) = accept(config, key, &buf[..n], source) else {
+            continue;
+        };
+
+        return Ok(Sample {
+            server: source,
+            packet,
+            offset: packet.transmit_time.diff(&t4) + config.broadcast_delay,
+            // The sync adds half of the round-trip delay.
+            delay: 2.0 * config.broadcast_delay,
+            reference_age,
+            received,
+        });
+    }
+}
+
+/// Returns the packet and reference age of a usable announcement,
+/// logging why any other datagram is ignored.
+fn accept(
+    config: &Config,
+    key: Option<&Key>,
+    announcement: &[u8],
+    source: SocketAddr,
+) -> Option<(Packet, f64)> {
+    match key {
+        Some(key) => {
+            if let Err(e) = key.verify(announcement) {
+                eprintln!("ignore broadcast from {}: {}", source, e);
+                return None;
+            }
+        }
+        None => eprintln!("accept unauthenticated broadcast from {}", source),
+    }
+
+    let packet = match Packet::decode(announcement) {
+        Ok(packet) if packet.mode == MODE_BROADCAST => packet,
+        Ok(_) => return None,
+        Err(e) => {
+            eprintln!("ignore broadcast from {}: {}", source, e);
+            return None;
+        }
+    };
+
+    // Unsynchronized servers and kiss codes don't carry a usable time.
+    if packet.stratum == 0 || packet.ref_time == Timestamp::default() {
+        eprintln!("ignore broadcast from unsynchronized server {}", source);
+        return None;
+    }
+
+    let reference_age = packet.transmit_time.diff(&packet.ref_time);
+    if config.max_reference_age > 0 && reference_age > config.max_reference_age as f64 {
+        eprintln!("ignore broadcast from stale server {}", source);
+        return None;
+    }
+
+    Some((packet, reference_age))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::packet::{MODE_SERVER, VERSION};
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn source() -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 2], NTP_PORT))
+    }
+
+    fn announcement(mode: u8, stratum: u8, reference_age: i64) -> Packet {
+        Packet {
+            version: VERSION,
+            mode,
+            stratum,
+            ref_time: Timestamp::from_unix(NOW - reference_age, 0),
+            transmit_time: Timestamp::from_unix(NOW, 0),
+            ..Default::default()
+        }
+    }
+
+    fn accepts(config: &Config, key: Option<&Key>, announcement: &[u8]) -> bool {
+        accept(config, key, announcement, source()).is_some()
+    }
+
+    #[test]
+    fn accept_broadcast() {
+        let config = Config::default();
+
+        let packet = announcement(MODE_BROADCAST, 2, 64);
+        let (accepted, reference_age) = accept(&config, None, &packet.encode(), source()).unwrap();
+        assert_eq!(accepted.transmit_time, packet.transmit_time);
+        assert_eq!(reference_age, 64.0);
+
+        // Replies to unicast requests aren't announcements.
+        let packet = announcement(MODE_SERVER, 2, 64);
+        assert!(!accepts(&config, None, &packet.encode()));
+
+        let packet = announcement(MODE_BROADCAST, 2, 64);
+        assert!(!accepts(&config, None, &packet.encode()[..40]));
+    }
+
+    #[test]
+    fn ignore_unsynchronized() {
+        let mut config = Config::default();
+
+        let packet = announcement(MODE_BROADCAST, 0, 64);
+        assert!(!accepts(&config, None, &packet.encode()));
+
+        let packet = Packet {
+            ref_time: Timestamp::default(),
+            ..announcement(MODE_BROADCAST, 2, 64)
+        };
+        assert!(!accepts(&config, None, &packet.encode()));
+
+        let packet = announcement(MODE_BROADCAST, 2, 2 * 86400);
+        assert!(!accepts(&config, None, &packet.encode()));
+        config.max_reference_age = 0;
+        assert!(accepts(&config, None, &packet.encode()));
+    }
+
+    #[tokio::test]
+    async fn require_mac() {
+        let config = Config::default();
+
+        let path =
+            std::env::temp_dir().join(format!("rsdsl_ntp-{}.broadcast.keys", std::process::id()));
+        std::fs::write(&path, "1 MD5 secret\n2 MD5 other\n").unwrap();
+        let key = Key::load(&path, 1).await.unwrap();
+        let other = Key::load(&path, 2).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut signed = announcement(MODE_BROADCAST, 2, 64).encode().to_vec();
+        assert!(!accepts(&config, Some(&key), &signed));
+
+        key.sign(&mut signed);
+        assert!(accepts(&config, Some(&key), &signed));
+        assert!(!accepts(&config, Some(&other), &signed));
+
+        let last = signed.len() - 1;
+        signed[last] ^= 1;
+        assert!(!accepts(&config, Some(&key), &signed));
+    }
+}
@@ -0,0 +1,489 @@
+use crate::auth::Key;
+use crate::config::Config;
+use crate::nts::Session;
+use crate::packet::{Packet, Timestamp, MODE_SERVER};
+use crate::socks;
+use crate::{Error, Result};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+
+/// Interface whose address is used with `bind_wan` if `interface` isn't set.
+const WAN_INTERFACE: &str = "ppp0";
+
+/// Limits the number of requests in flight at the same time
+/// to the `max_in_flight` it was created for.
+static IN_FLIGHT: Mutex<Option<(usize, Arc<Semaphore>)>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    /// Address the reply came from.
+    pub server: SocketAddr,
+    pub packet: Packet,
+    /// Seconds the local clock is behind the server.
+    pub offset: f64,
+    /// Round-trip delay in seconds.
+    pub delay: f64,
+    /// Seconds since the server last synchronized its own clock.
+    pub reference_age: f64,
+    pub received: Instant,
+}
+
+/// Returns the semaphore limiting the number of concurrent requests to `max`.
+/// A changed limit only applies to requests started afterwards,
+/// those already in flight keep their permits of the previous one.
+fn in_flight(max: usize) -> Arc<Semaphore> {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    match &*in_flight {
+        Some((limit, semaphore)) if *limit == max => semaphore.clone(),
+        _ => {
+            println!("limit concurrent requests to {}", max);
+
+            let semaphore = Arc::new(Semaphore::new(max));
+            *in_flight = Some((max, semaphore.clone()));
+            semaphore
+        }
+    }
+}
+
+/// Performs a single client/server exchange, signing the request
+/// and requiring a valid MAC on the reply if a key is specified.
+/// With an NTS session the request and reply are authenticated
+/// using NTS extension fields instead.
+/// The request is retransmitted up to `retransmits` times
+/// if no reply arrives within `retransmit_timeout`.
+/// Datagrams that aren't a valid reply are dropped while waiting.
+pub async fn request(
+    config: &Config,
+    server: SocketAddr,
+    key: Option<&Key>,
+    mut nts: Option<&mut Session>,
+) -> Result<Sample> {
+    let _permit = in_flight(config.max_in_flight)
+        .acquire_owned()
+        .await
+        .expect("semaphore is never closed");
+
+    // The control connection has to stay open until the exchange is done.
+    let proxy = match config.socks5_proxy {
+        Some(proxy) => Some(socks::associate(proxy).await?),
+        None => None,
+    };
+    let peer = proxy.as_ref().map_or(server, |(_, relay)| *relay);
+
+    let bind = match config.bind {
+        Some(bind) => Some(bind),
+        None if config.bind_wan => {
+            let interface = config.interface.as_deref().unwrap_or(WAN_INTERFACE);
+            match wan_address(interface, peer.is_ipv6())? {
+                Some(addr) => Some(SocketAddr::new(addr, 0)),
+                None => {
+                    eprintln!("no usable address on {}, using default source", interface);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let local: SocketAddr = match (peer, bind) {
+        (SocketAddr::V4(_), Some(bind @ SocketAddr::V4(_))) => bind,
+        (SocketAddr::V6(_), Some(bind @ SocketAddr::V6(_))) => bind,
+        (_, Some(bind)) => return Err(Error::BindFamily(bind, peer)),
+        (SocketAddr::V4(_), None) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        (SocketAddr::V6(_), None) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    if let Some(interface) = &config.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+
+    socket.connect(peer).await?;
+
+    let mut sent = Vec::new();
+    let mut reply = [0; 2048];
+    let mut dropped = None;
+    let proxied = proxy.is_some();
+
+    loop {
+        let t1 = Timestamp::now()?;
+
+        let mut buf = Packet::client(t1).encode().to_vec();
+        if let Some(session) = nts.as_deref_mut() {
+            session.seal(&mut buf)?;
+        } else if let Some(key) = key {
+            key.sign(&mut buf);
+        }
+
+        if proxied {
+            buf = socks::encapsulate(server, &buf);
+        }
+
+        // Depending on the kernel the ICMP error of an earlier request
+        // may be reported by this send instead of the previous receive.
+        let mut result = socket.send(&buf).await;
+        if let Err(e) = result.as_ref() {
+            if !sent.is_empty() && e.kind() == io::ErrorKind::ConnectionRefused {
+                eprintln!("can't receive from {}: {}", server, e);
+                result = socket.send(&buf).await;
+            }
+        }
+        result.map_err(|e| Error::SendFailed(server, e))?;
+        sent.push(t1);
+
+        // Anyone can send us a datagram, it mustn't end the exchange
+        // before the genuine reply had a chance to arrive.
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(config.retransmit_timeout);
+        while let Ok(n) = tokio::time::timeout_at(deadline, socket.recv(&mut reply)).await {
+            let n = match n {
+                Ok(n) => n,
+                Err(e) => {
+                    // An ICMP error caused by an earlier request, e.g. while
+                    // the server restarts, doesn't mean a retransmission fails.
+                    eprintln!("can't receive from {}: {}", server, e);
+                    dropped = Some(e.into());
+                    continue;
+                }
+            };
+            let (t4, received) = (Timestamp::now()?, Instant::now());
+
+            let datagram = &reply[..n];
+            match validate(server, datagram, proxied, nts.as_deref_mut(), key, &sent) {
+                Ok((packet, t1)) => return sample(config, server, packet, t1, t4, received),
+                Err(e) => {
+                    eprintln!("drop datagram: {}", e);
+                    dropped = Some(e);
+                }
+            }
+        }
+
+        if sent.len() > config.retransmits {
+            return Err(dropped.unwrap_or(Error::NoReply(server)));
+        }
+    }
+}
+
+/// Checks that a datagram is an authentic server reply to one of the
+/// requests that were `sent`, returning it and the transmit timestamp
+/// of that request.
+fn validate(
+    server: SocketAddr,
+    datagram: &[u8],
+    proxied: bool,
+    nts: Option<&mut Session>,
+    key: Option<&Key>,
+    sent: &[Timestamp],
+) -> Result<(Packet, Timestamp)> {
+    let malformed = |e| Error::MalformedReply(server, Box::new(e));
+
+    let reply = if proxied {
+        socks::decapsulate(datagram, server).map_err(malformed)?
+    } else {
+        datagram
+    };
+
+    if let Some(session) = nts {
+        session.open(server, reply)?;
+    } else if let Some(key) = key {
+        key.verify(reply)?;
+    }
+
+    let packet = Packet::decode(reply).map_err(malformed)?;
+    if packet.mode != MODE_SERVER {
+        return Err(malformed(Error::UnexpectedMode(packet.mode)));
+    }
+
+    // A late reply may belong to an earlier transmission. Replies that
+    // don't echo any of our transmit timestamps are spoofed or stale.
+    let t1 = sent
+        .iter()
+        .copied()
+        .find(|t1| *t1 == packet.orig_time)
+        .ok_or(Error::Spoofed(server))?;
+
+    Ok((packet, t1))
+}
+
+/// Checks a reply to the request sent at `t1` that arrived at `t4`,
+/// returning the sample it provides.
+fn sample(
+    config: &Config,
+    server: SocketAddr,
+    packet: Packet,
+    t1: Timestamp,
+    t4: Timestamp,
+    received: Instant,
+) -> Result<Sample> {
+    match packet.kiss_code().as_deref() {
+        Some("RATE") => return Err(Error::RateLimited(server, packet.poll_interval())),
+        Some(code) => return Err(Error::KissOfDeath(server, code.to_string())),
+        None => {}
+    }
+
+    // An unsynchronized server may still send a plausible transmit time.
+    if packet.ref_time == Timestamp::default() {
+        return Err(Error::NoReference);
+    }
+    let reference_age = packet.transmit_time.diff(&packet.ref_time);
+    if config.max_reference_age > 0 && reference_age > config.max_reference_age as f64 {
+        return Err(Error::StaleReference(reference_age));
+    }
+
+    let t2 = packet.recv_time;
+    let t3 = packet.transmit_time;
+
+    // The offset of a sample delayed by congestion is unreliable.
+    let delay = t4.diff(&t1) - t3.diff(&t2);
+    if config.max_delay > 0.0 && delay > config.max_delay {
+        return Err(Error::ExcessiveDelay(delay));
+    }
+
+    Ok(Sample {
+        server,
+        packet,
+        offset: (t2.diff(&t1) + t3.diff(&t4)) / 2.0,
+        delay,
+        reference_age,
+        received,
+    })
+}
+
+/// Returns the current address of an interface of the requested family,
+/// skipping IPv6 link-local addresses which can't reach NTP servers.
+fn wan_address(interface: &str, ipv6: bool) -> Result<Option<IpAddr>> {
+    let addr = nix::ifaddrs::getifaddrs()?
+        .filter(|ifaddr| ifaddr.interface_name == interface)
+        .filter_map(|ifaddr| ifaddr.address)
+        .find_map(|addr| {
+            if ipv6 {
+                let addr = *SocketAddrV6::from(*addr.as_sockaddr_in6()?).ip();
+                (addr.segments()[0] & 0xffc0 != 0xfe80).then_some(IpAddr::V6(addr))
+            } else {
+                let addr = *SocketAddrV4::from(*addr.as_sockaddr_in()?).ip();
+                Some(IpAddr::V4(addr))
+            }
+        });
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 123));
+    const T1: i64 = 1_700_000_000;
+
+    /// Returns a reply of a server 1 s ahead with a delay of 20 ms,
+    /// whose clock last synchronized `reference_age` seconds ago,
+    /// and the time it arrived at.
+    fn reply(reference_age: i64) -> (Packet, Timestamp) {
+        let packet = Packet {
+            version: 4,
+            mode: MODE_SERVER,
+            stratum: 2,
+            ref_time: Timestamp::from_unix(T1 + 1 - reference_age, 10_000_000),
+            orig_time: Timestamp::from_unix(T1, 0),
+            recv_time: Timestamp::from_unix(T1 + 1, 10_000_000),
+            transmit_time: Timestamp::from_unix(T1 + 1, 10_000_000),
+            ..Default::default()
+        };
+
+        (packet, Timestamp::from_unix(T1, 20_000_000))
+    }
+
+    fn check(config: &Config, packet: Packet, t4: Timestamp) -> Result<Sample> {
+        sample(config, SERVER, packet, packet.orig_time, t4, Instant::now())
+    }
+
+    #[test]
+    fn fresh_reference() {
+        let (packet, t4) = reply(64);
+        let sample = check(&Config::default(), packet, t4).unwrap();
+
+        assert!((sample.offset - 1.0).abs() < 1e-6);
+        assert!((sample.delay - 0.02).abs() < 1e-6);
+        assert!((sample.reference_age - 64.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stale_reference() {
+        let config = Config::default();
+
+        let (packet, t4) = reply(2 * 86400);
+        match check(&config, packet, t4) {
+            Err(Error::StaleReference(age)) => assert!((age - 2.0 * 86400.0).abs() < 1.0),
+            result => panic!("stale reference accepted: {:?}", result),
+        }
+
+        // The bound is inclusive.
+        let (packet, t4) = reply(config.max_reference_age as i64);
+        assert!(check(&config, packet, t4).is_ok());
+
+        // 0 disables the check.
+        let config = Config {
+            max_reference_age: 0,
+            ..Default::default()
+        };
+        let (packet, t4) = reply(365 * 86400);
+        assert!(check(&config, packet, t4).is_ok());
+    }
+
+    #[test]
+    fn wrong_originate() {
+        let (packet, _) = reply(64);
+        let (earlier, latest) = (Timestamp::from_unix(T1 - 1, 0), packet.orig_time);
+
+        // Late replies to earlier transmissions are still accepted.
+        let (_, t1) = validate(SERVER, &packet.encode(), false, None, None, &[latest]).unwrap();
+        assert_eq!(t1, latest);
+        let (_, t1) = validate(
+            SERVER,
+            &packet.encode(),
+            false,
+            None,
+            None,
+            &[earlier, latest],
+        )
+        .unwrap();
+        assert_eq!(t1, latest);
+
+        let spoofed = Packet {
+            orig_time: Timestamp::from_unix(T1, 1),
+            ..packet
+        };
+        assert!(matches!(
+            validate(
+                SERVER,
+                &spoofed.encode(),
+                false,
+                None,
+                None,
+                &[earlier, latest]
+            ),
+            Err(Error::Spoofed(SERVER))
+        ));
+    }
+
+    #[tokio::test]
+    async fn drop_wrong_originate() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            let (n, client) = server.recv_from(&mut buf).await.unwrap();
+            let request = Packet::decode(&buf[..n]).unwrap();
+
+            let (packet, _) = reply(64);
+            let genuine = Packet {
+                orig_time: request.transmit_time,
+                ..packet
+            };
+            let spoofed = Packet {
+                orig_time: Timestamp::default(),
+                ..genuine
+            };
+
+            server.send_to(&spoofed.encode(), client).await.unwrap();
+            server.send_to(&genuine.encode(), client).await.unwrap();
+        });
+
+        let config = Config {
+            retransmits: 0,
+            ..Default::default()
+        };
+        let sample = request(&config, addr, None, None).await.unwrap();
+        assert_eq!(sample.server, addr);
+        assert_eq!(sample.packet.stratum, 2);
+    }
+
+    #[tokio::test]
+    async fn retransmit_after_refused() {
+        // Nothing listens on the port until after the first request.
+        let addr = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let config = Config {
+            retransmits: 1,
+            ..Default::default()
+        };
+        let client = tokio::spawn(async move { request(&config, addr, None, None).await });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let server = UdpSocket::bind(addr).await.unwrap();
+
+        let mut buf = [0; 2048];
+        let (n, client_addr) =
+            tokio::time::timeout(Duration::from_secs(5), server.recv_from(&mut buf))
+                .await
+                .expect("no retransmission")
+                .unwrap();
+        let request = Packet::decode(&buf[..n]).unwrap();
+
+        let (packet, _) = reply(64);
+        let genuine = Packet {
+            orig_time: request.transmit_time,
+            ..packet
+        };
+        server
+            .send_to(&genuine.encode(), client_addr)
+            .await
+            .unwrap();
+
+        let sample = client.await.unwrap().unwrap();
+        assert_eq!(sample.packet.stratum, 2);
+    }
+
+    #[tokio::test]
+    async fn only_wrong_originate() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            let (_, client) = server.recv_from(&mut buf).await.unwrap();
+
+            let (packet, _) = reply(64);
+            server.send_to(&packet.encode(), client).await.unwrap();
+        });
+
+        let config = Config {
+            retransmits: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            request(&config, addr, None, None).await,
+            Err(Error::Spoofed(server)) if server == addr
+        ));
+    }
+
+    #[test]
+    fn zero_reference() {
+        let (mut packet, t4) = reply(64);
+        packet.ref_time = Timestamp::default();
+
+        assert!(matches!(
+            check(&Config::default(), packet, t4),
+            Err(Error::NoReference)
+        ));
+    }
+
+    #[test]
+    fn reload_in_flight_limit() {
+        let permit = in_flight(1).try_acquire_owned().unwrap();
+
+        // Requests in flight don't count against a new limit.
+        assert_eq!(in_flight(2).available_permits(), 2);
+        drop(permit);
+    }
+}
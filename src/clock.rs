@@ -0,0 +1,289 @@
+use crate::leap::{LEAP_DELETE, LEAP_INSERT};
+use crate::Result;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use nix::sys::time::TimeSpec;
+use nix::time::ClockId;
+
+/// Largest adjustment in seconds `adjtime` accepts.
+pub const MAX_SLEW: f64 = 2145.0;
+
+/// Rate at which the kernel slews the clock in seconds per second.
+pub const SLEW_RATE: f64 = 0.0005;
+
+/// Access to `CLOCK_REALTIME` and the kernel's clock discipline
+/// as needed by the sync logic, allowing it to run against a simulated clock.
+pub trait Clock {
+    /// Returns the current time in nanoseconds since the Unix epoch.
+    fn now_nanos(&self) -> Result<i64>;
+    /// Sets the time in nanoseconds since the Unix epoch.
+    fn set_nanos(&mut self, nanos: i64) -> Result<()>;
+    /// Steps the time by the specified number of seconds.
+    fn step(&mut self, secs: f64) -> Result<()>;
+    /// Gradually adjusts the time by the specified number of seconds.
+    fn slew(&mut self, secs: f64) -> Result<()>;
+    /// Sets the TAI-UTC offset in seconds.
+    fn set_tai_offset(&mut self, offset: i32) -> Result<()>;
+    /// Returns the frequency adjustment in scaled ppm (2^-16 ppm).
+    fn frequency(&self) -> Result<libc::c_long>;
+    /// Sets the frequency adjustment in scaled ppm (2^-16 ppm).
+    fn set_frequency(&mut self, freq: libc::c_long) -> Result<()>;
+    /// Sets or clears the pending leap second according to an NTP leap indicator.
+    fn arm_leap(&mut self, leap: u8) -> Result<()>;
+    /// Returns the clock state, e.g. `TIME_WAIT` after a leap second.
+    fn leap_state(&self) -> Result<libc::c_int>;
+}
+
+/// The kernel's `CLOCK_REALTIME`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> Result<i64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        Ok(i64::try_from(now.as_nanos())?)
+    }
+
+    fn set_nanos(&mut self, nanos: i64) -> Result<()> {
+        let timespec = TimeSpec::new(
+            nanos.div_euclid(1_000_000_000),
+            nanos.rem_euclid(1_000_000_000),
+        );
+        nix::time::clock_settime(ClockId::CLOCK_REALTIME, timespec)?;
+
+        Ok(())
+    }
+
+    fn step(&mut self, secs: f64) -> Result<()> {
+        step(secs)
+    }
+
+    fn slew(&mut self, secs: f64) -> Result<()> {
+        slew(secs)
+    }
+
+    fn set_tai_offset(&mut self, offset: i32) -> Result<()> {
+        set_tai_offset(offset)
+    }
+
+    fn frequency(&self) -> Result<libc::c_long> {
+        frequency()
+    }
+
+    fn set_frequency(&mut self, freq: libc::c_long) -> Result<()> {
+        set_frequency(freq)
+    }
+
+    fn arm_leap(&mut self, leap: u8) -> Result<()> {
+        arm_leap(leap)
+    }
+
+    fn leap_state(&self) -> Result<libc::c_int> {
+        leap_state()
+    }
+}
+
+/// Sets the kernel's TAI-UTC offset in seconds,
+/// making `CLOCK_TAI` run ahead of `CLOCK_REALTIME` by that amount.
+fn set_tai_offset(offset: i32) -> Result<()> {
+    // SAFETY: All zeroes is a valid `timex`.
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+
+    tx.modes = libc::ADJ_TAI;
+    tx.constant = offset.into();
+
+    adjtimex(&mut tx)?;
+    Ok(())
+}
+
+/// Steps `CLOCK_REALTIME` by the specified number of seconds.
+fn step(secs: f64) -> Result<()> {
+    let now = nix::time::clock_gettime(ClockId::CLOCK_REALTIME)?;
+    let delta = TimeSpec::from_duration(std::time::Duration::from_secs_f64(secs.abs()));
+
+    let stepped = if secs < 0.0 { now - delta } else { now + delta };
+    nix::time::clock_settime(ClockId::CLOCK_REALTIME, stepped)?;
+
+    Ok(())
+}
+
+/// Gradually adjusts `CLOCK_REALTIME` by the specified number of seconds
+/// (at most [`MAX_SLEW`]), replacing any adjustment still in progress.
+/// The kernel slews at 500 ppm, i.e. 0.5 ms per second.
+fn slew(secs: f64) -> Result<()> {
+    let micros = (secs.clamp(-MAX_SLEW, MAX_SLEW) * 1e6) as i64;
+    let delta = libc::timeval {
+        tv_sec: micros.div_euclid(1_000_000) as libc::time_t,
+        tv_usec: micros.rem_euclid(1_000_000) as libc::suseconds_t,
+    };
+
+    // SAFETY: `delta` is a valid `timeval` and the old delta isn't requested.
+    if unsafe { libc::adjtime(&delta, std::ptr::null_mut()) } == -1 {
+        return Err(Errno::last().into());
+    }
+
+    Ok(())
+}
+
+/// Returns the kernel's frequency adjustment in scaled ppm (2^-16 ppm).
+fn frequency() -> Result<libc::c_long> {
+    // SAFETY: All zeroes is a valid `timex`.
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+    adjtimex(&mut tx)?;
+
+    Ok(tx.freq)
+}
+
+/// Sets the kernel's frequency adjustment in scaled ppm (2^-16 ppm),
+/// making the clock run faster (positive) or slower (negative).
+fn set_frequency(freq: libc::c_long) -> Result<()> {
+    // SAFETY: All zeroes is a valid `timex`.
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+
+    tx.modes = libc::ADJ_FREQUENCY;
+    tx.freq = freq;
+
+    adjtimex(&mut tx)?;
+    Ok(())
+}
+
+/// Sets or clears the kernel's pending leap second
+/// according to an NTP leap indicator.
+fn arm_leap(leap: u8) -> Result<()> {
+    // SAFETY: All zeroes is a valid `timex`.
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+    adjtimex(&mut tx)?;
+
+    tx.modes = libc::ADJ_STATUS;
+    tx.status &= !(libc::STA_INS | libc::STA_DEL);
+    match leap {
+        LEAP_INSERT => tx.status |= libc::STA_INS,
+        LEAP_DELETE => tx.status |= libc::STA_DEL,
+        _ => {}
+    }
+
+    adjtimex(&mut tx)?;
+    Ok(())
+}
+
+/// Returns the kernel's clock state, e.g. `TIME_WAIT` after a leap second.
+fn leap_state() -> Result<libc::c_int> {
+    // SAFETY: All zeroes is a valid `timex`.
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+    adjtimex(&mut tx)
+}
+
+fn adjtimex(tx: &mut libc::timex) -> Result<libc::c_int> {
+    // SAFETY: `tx` is a valid, exclusively borrowed `timex`.
+    let state = unsafe { libc::adjtimex(tx) };
+    if state == -1 {
+        return Err(Errno::last().into());
+    }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::Clock;
+    use crate::Result;
+
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    /// A change a [`MockClock`] was asked to make.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Adjustment {
+        Set(i64),
+        Step(f64),
+        Slew(f64),
+        TaiOffset(i32),
+        Frequency(libc::c_long),
+        ArmLeap(u8),
+    }
+
+    /// A simulated clock that records the adjustments made to it.
+    /// Clones share the same state like the kernel's clock.
+    #[derive(Clone, Debug, Default)]
+    pub struct MockClock {
+        state: Arc<Mutex<MockState>>,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct MockState {
+        /// The time in nanoseconds since the Unix epoch, it doesn't advance.
+        pub nanos: i64,
+        pub frequency: libc::c_long,
+        pub leap_state: libc::c_int,
+        pub adjustments: Vec<Adjustment>,
+    }
+
+    impl MockClock {
+        pub fn new(nanos: i64) -> Self {
+            let clock = Self::default();
+            clock.state().nanos = nanos;
+            clock
+        }
+
+        pub fn state(&self) -> MutexGuard<'_, MockState> {
+            self.state.lock().unwrap()
+        }
+
+        /// Returns the adjustments made since the last call.
+        pub fn take_adjustments(&self) -> Vec<Adjustment> {
+            std::mem::take(&mut self.state().adjustments)
+        }
+
+        fn record(&mut self, adjustment: Adjustment) {
+            self.state().adjustments.push(adjustment);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now_nanos(&self) -> Result<i64> {
+            Ok(self.state().nanos)
+        }
+
+        fn set_nanos(&mut self, nanos: i64) -> Result<()> {
+            self.state().nanos = nanos;
+            self.record(Adjustment::Set(nanos));
+            Ok(())
+        }
+
+        fn step(&mut self, secs: f64) -> Result<()> {
+            self.state().nanos += (secs * 1e9) as i64;
+            self.record(Adjustment::Step(secs));
+            Ok(())
+        }
+
+        fn slew(&mut self, secs: f64) -> Result<()> {
+            self.record(Adjustment::Slew(secs));
+            Ok(())
+        }
+
+        fn set_tai_offset(&mut self, offset: i32) -> Result<()> {
+            self.record(Adjustment::TaiOffset(offset));
+            Ok(())
+        }
+
+        fn frequency(&self) -> Result<libc::c_long> {
+            Ok(self.state().frequency)
+        }
+
+        fn set_frequency(&mut self, freq: libc::c_long) -> Result<()> {
+            self.state().frequency = freq;
+            self.record(Adjustment::Frequency(freq));
+            Ok(())
+        }
+
+        fn arm_leap(&mut self, leap: u8) -> Result<()> {
+            self.record(Adjustment::ArmLeap(leap));
+            Ok(())
+        }
+
+        fn leap_state(&self) -> Result<libc::c_int> {
+            Ok(self.state().leap_state)
+        }
+    }
+}
@@ -0,0 +1,775 @@
+use crate::{Error, Result, MAX_UNIX};
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::fs;
+
+use serde::{Deserialize, Serialize};
+
+const CONFIG_PATH: &str = "/data/ntp.conf";
+
+/// Polling faster than this is considered abusive by the NTP pool.
+const MIN_POLL: u64 = 16;
+
+/// Upper bound for all durations (10 years) so that adding them
+/// to the current time can't overflow.
+const MAX_SECONDS: u64 = 10 * 365 * 86400;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Keep the clock synchronized.
+    #[default]
+    Discipline,
+    /// Only measure and report the offset, leaving the clock
+    /// to another source (except for the first sync
+    /// if `monitor_initial_sync` is set).
+    Monitor,
+    /// Synchronize the clock once and exit, like `--oneshot`.
+    Oneshot,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    /// Prefer IPv6 if it is routable, fall back to IPv4 otherwise.
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsSource {
+    /// Use `dns_servers`.
+    #[default]
+    Static,
+    /// Use the nameservers from `resolv_conf`, falling back to `dns_servers`.
+    Dynamic,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PollMode {
+    /// Always poll at `interval`.
+    #[default]
+    Fixed,
+    /// Lengthen the interval while the clock is stable
+    /// and shorten it when it drifts, starting at `interval`.
+    Adaptive,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogAction {
+    /// Exit with an error so that the supervisor restarts the daemon.
+    #[default]
+    Exit,
+    /// Wait for the link again and start over with fresh resolvers
+    /// and server statistics.
+    Restart,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockKind {
+    /// Only discipline `CLOCK_REALTIME` (UTC).
+    #[default]
+    Realtime,
+    /// Additionally set the kernel's TAI offset so that `CLOCK_TAI` is correct.
+    Tai,
+}
+
+/// An IP network in CIDR notation, e.g. `192.0.2.0/24` or `2001:db8::/32`.
+/// A plain address is a network of a single host.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    /// Reports whether an address is part of the network.
+    pub fn contains(&self, ip_addr: IpAddr) -> bool {
+        match (self.addr, ip_addr) {
+            (IpAddr::V4(net), IpAddr::V4(ip_addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip_addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip_addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip_addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s.as_str(), None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid network address in {}", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl From<Network> for String {
+    fn from(network: Network) -> Self {
+        network.to_string()
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub mode: Mode,
+    pub monitor_initial_sync: bool,
+    pub servers: Vec<String>,
+    pub max_servers: usize,
+    pub address_family: AddressFamily,
+    pub happy_eyeballs: bool,
+    pub max_addresses: usize,
+    pub max_in_flight: usize,
+    pub dns_source: DnsSource,
+    pub resolv_conf: PathBuf,
+    pub dns_servers: Vec<SocketAddr>,
+    pub dns_fallback: bool,
+    pub dns_timeout: u64,
+    pub dns_attempts: usize,
+    pub allowed_networks: Vec<Network>,
+    pub denied_networks: Vec<Network>,
+    pub link_timeout: u64,
+    pub link_settle: u64,
+    pub startup_jitter: u64,
+    pub initial_interval: u64,
+    pub interval: u64,
+    pub poll_mode: PollMode,
+    pub min_poll: u64,
+    pub max_poll: u64,
+    pub attempts: usize,
+    pub max_consecutive_failures: u32,
+    pub watchdog_timeout: u64,
+    pub watchdog_action: WatchdogAction,
+    pub blacklist_after: u32,
+    pub blacklist_cooldown: u64,
+    pub retry_delay: u64,
+    pub request_timeout: u64,
+    pub retransmits: usize,
+    pub retransmit_timeout: u64,
+    pub burst: usize,
+    pub burst_interval: u64,
+    pub max_reference_age: u64,
+    pub min_time: Option<i64>,
+    pub max_delay: f64,
+    pub static_offset_ms: f64,
+    pub deadband: f64,
+    pub verify: bool,
+    pub verify_threshold: f64,
+    pub verify_revert: bool,
+    pub cross_check: usize,
+    pub cross_check_tolerance: f64,
+    pub never_step_after_first: bool,
+    pub panic_threshold: u64,
+    pub asymmetry_check: bool,
+    pub bind: Option<SocketAddr>,
+    pub bind_wan: bool,
+    pub interface: Option<String>,
+    pub socks5_proxy: Option<SocketAddr>,
+    pub refclock: Option<PathBuf>,
+    pub refclock_primary: bool,
+    pub refclock_offset_ms: f64,
+    pub broadcast: bool,
+    pub broadcast_timeout: u64,
+    pub broadcast_delay: f64,
+    pub clock_id: ClockKind,
+    pub tai_offset: i32,
+    pub leap_seconds: bool,
+    pub leap_smear: bool,
+    pub leap_smear_window: u64,
+    pub keys: PathBuf,
+    pub key_id: Option<u32>,
+    pub nts_servers: Vec<String>,
+    pub status_socket: PathBuf,
+    pub sync_request_interval: u64,
+    pub event_log: Option<PathBuf>,
+    pub event_log_size: u64,
+    pub history_size: usize,
+    pub health_max_age: u64,
+    pub notify_dhcp6: bool,
+    pub notify_confirmations: u32,
+    pub on_first_sync: Option<String>,
+    pub on_sync: Option<String>,
+    pub hook_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            monitor_initial_sync: false,
+            servers: vec!["2.pool.ntp.org".into()],
+            max_servers: 4,
+            address_family: AddressFamily::default(),
+            happy_eyeballs: true,
+            max_addresses: 3,
+            max_in_flight: 3,
+            dns_source: DnsSource::default(),
+            resolv_conf: PathBuf::from("/etc/resolv.conf"),
+            dns_servers: vec![SocketAddr::from(([0x2620, 0xfe, 0, 0, 0, 0, 0, 0xfe], 53))],
+            dns_fallback: false,
+            dns_timeout: 2,
+            dns_attempts: 1,
+            allowed_networks: Vec::new(),
+            denied_networks: Vec::new(),
+            link_timeout: 300,
+            link_settle: 2,
+            startup_jitter: 0,
+            initial_interval: 30,
+            interval: 3600,
+            poll_mode: PollMode::default(),
+            min_poll: MIN_POLL,
+            max_poll: 86400,
+            attempts: 3,
+            max_consecutive_failures: 0,
+            watchdog_timeout: 86400,
+            watchdog_action: WatchdogAction::default(),
+            blacklist_after: 3,
+            blacklist_cooldown: 3600,
+            retry_delay: 2,
+            request_timeout: 5,
+            retransmits: 3,
+            retransmit_timeout: 1,
+            burst: 4,
+            burst_interval: 2,
+            min_time: None,
+            max_reference_age: 86400,
+            max_delay: 1.0,
+            static_offset_ms: 0.0,
+            deadband: 0.0,
+            verify: false,
+            verify_threshold: 0.1,
+            verify_revert: false,
+            cross_check: 0,
+            cross_check_tolerance: 0.2,
+            never_step_after_first: false,
+            panic_threshold: 1000,
+            asymmetry_check: false,
+            bind: None,
+            bind_wan: false,
+            interface: None,
+            socks5_proxy: None,
+            refclock: None,
+            refclock_primary: false,
+            refclock_offset_ms: 0.0,
+            broadcast: false,
+            broadcast_timeout: 128,
+            broadcast_delay: 0.004,
+            clock_id: ClockKind::default(),
+            tai_offset: 37,
+            leap_seconds: false,
+            leap_smear: false,
+            leap_smear_window: 86400,
+            keys: PathBuf::from("/data/ntp.keys"),
+            key_id: None,
+            nts_servers: Vec::new(),
+            status_socket: PathBuf::from("/run/ntp.sock"),
+            sync_request_interval: 10,
+            event_log: Some(PathBuf::from("/data/ntp.events.jsonl")),
+            event_log_size: 256 * 1024,
+            history_size: 32,
+            health_max_age: 7200,
+            notify_dhcp6: true,
+            notify_confirmations: 1,
+            on_first_sync: None,
+            on_sync: None,
+            hook_timeout: 30,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the JSON configuration file, falling back to the defaults
+    /// if it doesn't exist.
+    pub async fn load() -> Result<Self> {
+        let config: Self = match fs::read(CONFIG_PATH).await {
+            Ok(buf) => serde_json::from_slice(&buf)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Clamps a poll interval in seconds to the configured bounds.
+    pub fn poll_interval(&self, secs: u64) -> Duration {
+        Duration::from_secs(secs.clamp(self.min_poll, self.max_poll))
+    }
+
+    /// Reports whether a resolved server address may be queried.
+    /// Denied networks take precedence, an empty allowlist permits everything.
+    pub fn permits(&self, ip_addr: IpAddr) -> bool {
+        !self
+            .denied_networks
+            .iter()
+            .any(|network| network.contains(ip_addr))
+            && (self.allowed_networks.is_empty()
+                || self
+                    .allowed_networks
+                    .iter()
+                    .any(|network| network.contains(ip_addr)))
+    }
+
+    /// Returns at most `max_servers` servers to query during a poll.
+    /// Longer server lists are sampled in consecutive slices
+    /// so that every server is considered eventually.
+    pub fn sample_servers(&self, poll: usize) -> Vec<String> {
+        if self.servers.len() <= self.max_servers {
+            return self.servers.clone();
+        }
+
+        let start = poll.wrapping_mul(self.max_servers) % self.servers.len();
+        self.servers
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.max_servers)
+            .cloned()
+            .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.servers.is_empty() {
+            return Err(Error::NoServers);
+        }
+        if self.dns_timeout == 0 || self.dns_attempts == 0 {
+            return Err(Error::InvalidConfig(
+                "dns_timeout and dns_attempts must be at least 1".into(),
+            ));
+        }
+        if self.max_servers == 0 {
+            return Err(Error::InvalidConfig(
+                "max_servers must be at least 1".into(),
+            ));
+        }
+        if self.notify_confirmations == 0 {
+            return Err(Error::InvalidConfig(
+                "notify_confirmations must be at least 1".into(),
+            ));
+        }
+        if self.max_addresses == 0 {
+            return Err(Error::InvalidConfig(
+                "max_addresses must be at least 1".into(),
+            ));
+        }
+        if self.max_in_flight == 0 {
+            return Err(Error::InvalidConfig(
+                "max_in_flight must be at least 1".into(),
+            ));
+        }
+
+        let durations = [
+            ("dns_timeout", self.dns_timeout),
+            ("link_timeout", self.link_timeout),
+            ("link_settle", self.link_settle),
+            ("startup_jitter", self.startup_jitter),
+            ("initial_interval", self.initial_interval),
+            ("interval", self.interval),
+            ("min_poll", self.min_poll),
+            ("max_poll", self.max_poll),
+            ("watchdog_timeout", self.watchdog_timeout),
+            ("blacklist_cooldown", self.blacklist_cooldown),
+            ("retry_delay", self.retry_delay),
+            ("request_timeout", self.request_timeout),
+            ("retransmit_timeout", self.retransmit_timeout),
+            ("burst_interval", self.burst_interval),
+            ("broadcast_timeout", self.broadcast_timeout),
+            ("max_reference_age", self.max_reference_age),
+            ("panic_threshold", self.panic_threshold),
+            ("leap_smear_window", self.leap_smear_window),
+            ("sync_request_interval", self.sync_request_interval),
+            ("hook_timeout", self.hook_timeout),
+            ("health_max_age", self.health_max_age),
+        ];
+        if let Some((name, _)) = durations.iter().find(|(_, secs)| *secs > MAX_SECONDS) {
+            return Err(Error::InvalidConfig(format!(
+                "{} must be at most {} seconds",
+                name, MAX_SECONDS
+            )));
+        }
+
+        if self.min_poll < MIN_POLL {
+            return Err(Error::InvalidConfig(format!(
+                "min_poll must be at least {} seconds",
+                MIN_POLL
+            )));
+        }
+        if self.max_poll < self.min_poll {
+            return Err(Error::InvalidConfig(
+                "max_poll must not be less than min_poll".into(),
+            ));
+        }
+        if self.attempts == 0 {
+            return Err(Error::InvalidConfig("attempts must be at least 1".into()));
+        }
+
+        if !(self.max_delay >= 0.0 && self.max_delay.is_finite()) {
+            return Err(Error::InvalidConfig(
+                "max_delay must not be negative".into(),
+            ));
+        }
+        if !(self.broadcast_delay >= 0.0 && self.broadcast_delay.is_finite()) {
+            return Err(Error::InvalidConfig(
+                "broadcast_delay must not be negative".into(),
+            ));
+        }
+        if !(self.deadband >= 0.0 && self.deadband.is_finite()) {
+            return Err(Error::InvalidConfig("deadband must not be negative".into()));
+        }
+        // The servers sampled during a poll have to include enough others.
+        if self.cross_check > 0 && self.cross_check >= self.max_servers.min(self.servers.len()) {
+            return Err(Error::InvalidConfig(
+                "cross_check must be less than the number of servers sampled per poll".into(),
+            ));
+        }
+        if !(self.cross_check_tolerance >= 0.0 && self.cross_check_tolerance.is_finite()) {
+            return Err(Error::InvalidConfig(
+                "cross_check_tolerance must not be negative".into(),
+            ));
+        }
+        if !(self.verify_threshold >= 0.0 && self.verify_threshold.is_finite()) {
+            return Err(Error::InvalidConfig(
+                "verify_threshold must not be negative".into(),
+            ));
+        }
+
+        if self
+            .min_time
+            .is_some_and(|min_time| !(0..=MAX_UNIX).contains(&min_time))
+        {
+            return Err(Error::InvalidConfig(format!(
+                "min_time must be a Unix time between 0 and {}",
+                MAX_UNIX
+            )));
+        }
+
+        if !(-1000.0..=1000.0).contains(&self.static_offset_ms) {
+            return Err(Error::InvalidConfig(
+                "static_offset_ms must be between -1000 and 1000".into(),
+            ));
+        }
+        if !(-1000.0..=1000.0).contains(&self.refclock_offset_ms) {
+            return Err(Error::InvalidConfig(
+                "refclock_offset_ms must be between -1000 and 1000".into(),
+            ));
+        }
+
+        // The kernel can't slew faster than 500 ppm and the smear
+        // has to start on the day of the leap second.
+        if self.leap_smear && !(2000..=86400).contains(&self.leap_smear_window) {
+            return Err(Error::InvalidConfig(
+                "leap_smear_window must be between 2000 and 86400 seconds".into(),
+            ));
+        }
+
+        // TAI has been 10 seconds ahead of UTC in 1972 and leap seconds
+        // are only inserted, so anything outside of this range is a mistake.
+        if self.clock_id == ClockKind::Tai && !(10..=100).contains(&self.tai_offset) {
+            return Err(Error::InvalidConfig(format!(
+                "tai_offset {} is implausible, it must be the current TAI-UTC \
+                difference in seconds (37 since 2017) as CLOCK_TAI is derived \
+                by adding it to CLOCK_REALTIME",
+                self.tai_offset
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Validates the defaults with some fields changed.
+    fn validate(change: impl FnOnce(&mut Config)) -> Result<()> {
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate()
+    }
+
+    fn assert_invalid(result: Result<()>) {
+        assert!(
+            matches!(result, Err(Error::InvalidConfig(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        validate(|_| {}).unwrap();
+    }
+
+    #[test]
+    fn at_least_one() {
+        assert_invalid(validate(|config| config.attempts = 0));
+        assert_invalid(validate(|config| config.dns_timeout = 0));
+        assert_invalid(validate(|config| config.dns_attempts = 0));
+        assert_invalid(validate(|config| config.max_servers = 0));
+        assert_invalid(validate(|config| config.max_addresses = 0));
+        assert_invalid(validate(|config| config.max_in_flight = 0));
+        assert_invalid(validate(|config| config.notify_confirmations = 0));
+        assert!(matches!(
+            validate(|config| config.servers.clear()),
+            Err(Error::NoServers)
+        ));
+
+        validate(|config| {
+            config.attempts = 1;
+            config.max_servers = 1;
+            config.cross_check = 0;
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn durations() {
+        validate(|config| {
+            config.interval = MAX_SECONDS;
+            config.max_poll = MAX_SECONDS;
+            config.watchdog_timeout = MAX_SECONDS;
+        })
+        .unwrap();
+
+        assert_invalid(validate(|config| config.max_poll = MAX_SECONDS + 1));
+        assert_invalid(validate(|config| config.watchdog_timeout = u64::MAX));
+        assert_invalid(validate(|config| config.startup_jitter = u64::MAX));
+
+        validate(|config| config.min_poll = MIN_POLL).unwrap();
+        assert_invalid(validate(|config| config.min_poll = MIN_POLL - 1));
+        assert_invalid(validate(|config| config.max_poll = config.min_poll - 1));
+        validate(|config| config.max_poll = config.min_poll).unwrap();
+    }
+
+    #[test]
+    fn floats() {
+        for invalid in [-0.001, f64::NAN, f64::INFINITY] {
+            assert_invalid(validate(|config| config.max_delay = invalid));
+            assert_invalid(validate(|config| config.broadcast_delay = invalid));
+            assert_invalid(validate(|config| config.deadband = invalid));
+            assert_invalid(validate(|config| config.cross_check_tolerance = invalid));
+            assert_invalid(validate(|config| config.verify_threshold = invalid));
+        }
+
+        validate(|config| config.static_offset_ms = -1000.0).unwrap();
+        validate(|config| config.static_offset_ms = 1000.0).unwrap();
+        for invalid in [-1000.001, 1000.001, f64::NAN] {
+            assert_invalid(validate(|config| config.static_offset_ms = invalid));
+            assert_invalid(validate(|config| config.refclock_offset_ms = invalid));
+        }
+    }
+
+    #[test]
+    fn min_time() {
+        validate(|config| config.min_time = Some(0)).unwrap();
+        validate(|config| config.min_time = Some(MAX_UNIX)).unwrap();
+
+        assert_invalid(validate(|config| config.min_time = Some(-1)));
+        assert_invalid(validate(|config| config.min_time = Some(MAX_UNIX + 1)));
+        assert_invalid(validate(|config| config.min_time = Some(i64::MAX)));
+    }
+
+    #[test]
+    fn cross_check() {
+        let servers = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+        validate(|config| {
+            config.servers = servers.clone();
+            config.cross_check = 2;
+        })
+        .unwrap();
+        assert_invalid(validate(|config| {
+            config.servers = servers.clone();
+            config.cross_check = 3;
+        }));
+        assert_invalid(validate(|config| {
+            config.servers = servers.clone();
+            config.max_servers = 2;
+            config.cross_check = 2;
+        }));
+    }
+
+    #[test]
+    fn leap_smear_window() {
+        let smear = |window| {
+            move |config: &mut Config| {
+                config.leap_smear = true;
+                config.leap_smear_window = window;
+            }
+        };
+
+        validate(smear(2000)).unwrap();
+        validate(smear(86400)).unwrap();
+        assert_invalid(validate(smear(1999)));
+        assert_invalid(validate(smear(86401)));
+
+        // Only checked if smearing.
+        validate(|config| config.leap_smear_window = 0).unwrap();
+    }
+
+    #[test]
+    fn tai_offset() {
+        let tai = |offset| {
+            move |config: &mut Config| {
+                config.clock_id = ClockKind::Tai;
+                config.tai_offset = offset;
+            }
+        };
+
+        validate(tai(10)).unwrap();
+        validate(tai(100)).unwrap();
+        assert_invalid(validate(tai(9)));
+        assert_invalid(validate(tai(101)));
+    }
+
+    #[test]
+    fn parse_out_of_range() {
+        // Negative or huge durations don't even parse.
+        assert!(serde_json::from_str::<Config>(r#"{"interval": -1}"#).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{"interval": 1e30}"#).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{"interval": 18446744073709551616}"#).is_err());
+
+        let config: Config = serde_json::from_str(r#"{"interval": 18446744073709551615}"#).unwrap();
+        assert_invalid(config.validate());
+    }
+
+    fn network(s: &str) -> Network {
+        Network::try_from(s.to_string()).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_network() {
+        assert_eq!(network("192.0.2.0/24").to_string(), "192.0.2.0/24");
+        assert_eq!(network("192.0.2.1").to_string(), "192.0.2.1/32");
+        assert_eq!(network("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(network("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(network("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(network("::/0").to_string(), "::/0");
+
+        for invalid in [
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/-1",
+            "192.0.2.0/",
+            "192.0.2.0/x",
+            "192.0.2/24",
+            "example.org/24",
+            "",
+        ] {
+            assert!(
+                Network::try_from(invalid.to_string()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn network_contains() {
+        let v4 = network("192.0.2.0/24");
+        assert!(v4.contains(ip("192.0.2.0")));
+        assert!(v4.contains(ip("192.0.2.255")));
+        assert!(!v4.contains(ip("192.0.3.0")));
+        assert!(!v4.contains(ip("192.0.1.255")));
+        assert!(!v4.contains(ip("::ffff:192.0.2.1")));
+
+        let host = network("192.0.2.1/32");
+        assert!(host.contains(ip("192.0.2.1")));
+        assert!(!host.contains(ip("192.0.2.2")));
+
+        let any = network("0.0.0.0/0");
+        assert!(any.contains(ip("0.0.0.0")));
+        assert!(any.contains(ip("255.255.255.255")));
+        assert!(!any.contains(ip("2001:db8::1")));
+
+        let v6 = network("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8::")));
+        assert!(v6.contains(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!v6.contains(ip("2001:db9::")));
+        assert!(!v6.contains(ip("192.0.2.1")));
+
+        let host = network("2001:db8::1/128");
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::2")));
+
+        let any = network("::/0");
+        assert!(any.contains(ip("::")));
+        assert!(any.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!any.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn permits() {
+        assert!(Config::default().permits(ip("192.0.2.1")));
+
+        let config = Config {
+            allowed_networks: vec![network("192.0.2.0/24"), network("2001:db8::/32")],
+            denied_networks: vec![network("192.0.2.128/25")],
+            ..Default::default()
+        };
+        assert!(config.permits(ip("192.0.2.1")));
+        assert!(config.permits(ip("2001:db8::1")));
+        assert!(!config.permits(ip("198.51.100.1")));
+        assert!(!config.permits(ip("2001:db9::1")));
+        // Denied networks take precedence.
+        assert!(!config.permits(ip("192.0.2.128")));
+        assert!(!config.permits(ip("192.0.2.255")));
+
+        let config = Config {
+            denied_networks: vec![network("198.51.100.0/24")],
+            ..Default::default()
+        };
+        assert!(config.permits(ip("192.0.2.1")));
+        assert!(!config.permits(ip("198.51.100.1")));
+    }
+}
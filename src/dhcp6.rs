@@ -0,0 +1,249 @@
+use std::time::Duration;
+
+use sysinfo::{ProcessExt, Signal, System, SystemExt};
+use tokio::sync::mpsc;
+
+const PROCESS_NAME: &str = "rsdsl_dhcp6";
+const NOTIFY_ATTEMPTS: usize = 5;
+const NOTIFY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Finds processes by name to signal them,
+/// allowing the notification to be tested without real processes.
+pub trait ProcessLister {
+    /// Sends SIGUSR2 to all processes named `name`,
+    /// returning whether any of them was signaled.
+    fn signal(&mut self, name: &str) -> bool;
+}
+
+/// The processes of the running system.
+pub struct SystemProcesses;
+
+impl ProcessLister for SystemProcesses {
+    fn signal(&mut self, name: &str) -> bool {
+        // Only the process list is needed, not CPU, memory or disk information.
+        let mut system = System::new();
+        system.refresh_processes();
+
+        let mut signaled = false;
+        for process in system.processes_by_exact_name(name) {
+            signaled |= process.kill_with(Signal::User2).unwrap_or(false);
+        }
+
+        signaled
+    }
+}
+
+/// The result of a poll as far as the notification is concerned.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Tick {
+    /// A successful sync with the number of syncs in a row
+    /// required before notifying.
+    Synced(u32),
+    Failed,
+}
+
+/// Tells rsdsl_dhcp6 once that the clock is synchronized.
+/// The notification stays pending until dhcp6 could be signaled
+/// as it may not be running yet if the first sync was fast.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    confirmations: u32,
+    pending: bool,
+    notified: bool,
+}
+
+impl Notifier {
+    /// Records a successful sync, making the notification pending
+    /// once `required` syncs in a row succeeded.
+    pub fn record_success(&mut self, required: u32) {
+        if self.notified {
+            return;
+        }
+
+        // A single sample may be off, dependents can ask for more.
+        self.confirmations += 1;
+        if self.confirmations >= required {
+            self.pending = true;
+            self.notified = true;
+        } else {
+            println!(
+                "sync {}/{} before notifying {}",
+                self.confirmations, required, PROCESS_NAME
+            );
+        }
+    }
+
+    /// Records a failed sync, the confirmations have to start over.
+    pub fn record_failure(&mut self) {
+        self.confirmations = 0;
+    }
+
+    /// Signals dhcp6 if the notification is pending, retrying for a short
+    /// grace period if it isn't running. Returns whether the notification
+    /// is still pending, it is retried on the next call.
+    pub async fn deliver(&mut self, processes: &mut impl ProcessLister) -> bool {
+        if !self.pending {
+            return false;
+        }
+
+        for attempt in 1..=NOTIFY_ATTEMPTS {
+            if processes.signal(PROCESS_NAME) {
+                self.pending = false;
+                return false;
+            }
+
+            if attempt < NOTIFY_ATTEMPTS {
+                tokio::time::sleep(NOTIFY_RETRY_DELAY).await;
+            }
+        }
+
+        true
+    }
+}
+
+/// Runs the notification in its own task so that the grace period
+/// doesn't hold up signal handling or the poll schedule.
+/// The outcome of every poll is to be sent to the returned channel,
+/// a pending notification is retried after each one.
+pub fn spawn(mut processes: impl ProcessLister + Send + 'static) -> mpsc::UnboundedSender<Tick> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut notifier = Notifier::default();
+        while let Some(tick) = rx.recv().await {
+            match tick {
+                Tick::Synced(required) => notifier.record_success(required),
+                Tick::Failed => notifier.record_failure(),
+            }
+
+            if notifier.deliver(&mut processes).await {
+                eprintln!("can't find {}, retrying next tick", PROCESS_NAME);
+            }
+        }
+    });
+
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Reports dhcp6 as running after a number of lookups.
+    struct MockProcesses {
+        lookups: Arc<AtomicUsize>,
+        running_after: usize,
+    }
+
+    impl MockProcesses {
+        fn new(running_after: usize) -> Self {
+            Self {
+                lookups: Arc::default(),
+                running_after,
+            }
+        }
+
+        fn lookups(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    impl ProcessLister for MockProcesses {
+        fn signal(&mut self, name: &str) -> bool {
+            assert_eq!(name, PROCESS_NAME);
+
+            self.lookups.fetch_add(1, Ordering::SeqCst) + 1 > self.running_after
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latch_until_signaled() {
+        let mut notifier = Notifier::default();
+        let mut processes = MockProcesses::new(NOTIFY_ATTEMPTS + 2);
+
+        // Nothing to deliver before the first sync.
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), 0);
+
+        notifier.record_success(1);
+
+        // dhcp6 isn't running during the grace period of the first tick.
+        assert!(notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), NOTIFY_ATTEMPTS);
+
+        // It appears during the next one.
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), NOTIFY_ATTEMPTS + 3);
+
+        // The notification is only ever sent once.
+        notifier.record_success(1);
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), NOTIFY_ATTEMPTS + 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn grace_period() {
+        let mut notifier = Notifier::default();
+        let mut processes = MockProcesses::new(2);
+
+        notifier.record_success(1);
+
+        let start = tokio::time::Instant::now();
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), 3);
+        assert_eq!(start.elapsed(), 2 * NOTIFY_RETRY_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn require_two_confirmations() {
+        let mut notifier = Notifier::default();
+        let mut processes = MockProcesses::new(0);
+
+        // A single sync isn't enough.
+        notifier.record_success(2);
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), 0);
+
+        // A failure in between starts over.
+        notifier.record_failure();
+        notifier.record_success(2);
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), 0);
+
+        notifier.record_success(2);
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), 1);
+
+        // Later failures don't cause another notification.
+        notifier.record_failure();
+        notifier.record_success(2);
+        notifier.record_success(2);
+        assert!(!notifier.deliver(&mut processes).await);
+        assert_eq!(processes.lookups(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_in_background() {
+        let processes = MockProcesses::new(2);
+        let lookups = processes.lookups.clone();
+
+        let dhcp6 = spawn(processes);
+
+        // Sending never waits for the grace period.
+        let start = tokio::time::Instant::now();
+        dhcp6.send(Tick::Failed).unwrap();
+        dhcp6.send(Tick::Synced(1)).unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        tokio::time::sleep(3 * NOTIFY_RETRY_DELAY).await;
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+        // Later syncs don't cause another notification.
+        dhcp6.send(Tick::Synced(1)).unwrap();
+        tokio::time::sleep(NOTIFY_RETRY_DELAY).await;
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,542 @@
+use crate::config::{AddressFamily, Config, DnsSource};
+use crate::{Error, Result, NTP_PORT};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::fs;
+
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::{AsyncResolver, TokioAsyncResolver};
+
+/// Public resolvers appended to the configured ones if `dns_fallback` is set.
+const FALLBACK_DNS_SERVERS: &[&str] = &[
+    "[2606:4700:4700::1111]:53",
+    "1.1.1.1:53",
+    "[2620:fe::9]:53",
+    "9.9.9.9:53",
+];
+
+/// Resolvers are recreated after failing this many times in a row
+/// in case they got stuck in a bad state.
+const MAX_FAILURES: u32 = 3;
+
+/// Caches a resolver per DNS server across polls.
+#[derive(Default)]
+pub struct Resolver {
+    resolvers: HashMap<SocketAddr, (TokioAsyncResolver, u32)>,
+    recreations: u64,
+    /// The `dns_timeout` and `dns_attempts` the resolvers were created with.
+    opts: (u64, usize),
+    /// The address of each hostname that produced the last good sample.
+    preferred: HashMap<String, IpAddr>,
+}
+
+impl Resolver {
+    /// Remembers the address of a hostname that produced a good sample
+    /// so that it's tried first on the next poll.
+    pub fn prefer(&mut self, hostname: &str, ip_addr: IpAddr) {
+        self.preferred.insert(hostname.to_string(), ip_addr);
+    }
+
+    /// Returns the address of a hostname that produced the last good sample.
+    pub fn preferred(&self, hostname: &str) -> Option<IpAddr> {
+        self.preferred.get(hostname).copied()
+    }
+
+    /// Falls back to trying the addresses of a hostname round-robin.
+    pub fn forget_preferred(&mut self, hostname: &str) {
+        self.preferred.remove(hostname);
+    }
+
+    /// Resolves a hostname using the configured resolvers in order,
+    /// returning the answer of the first one that succeeds.
+    /// The addresses of the preferred family come first.
+    /// IP addresses are returned as they are without querying any resolver.
+    pub async fn resolve(&mut self, config: &Config, hostname: &str) -> Result<Vec<IpAddr>> {
+        // Literal addresses don't depend on DNS being available.
+        if let Ok(ip_addr) = hostname.parse() {
+            return permitted_addrs(config, hostname, vec![ip_addr]);
+        }
+
+        let opts = (config.dns_timeout, config.dns_attempts);
+        if opts != self.opts {
+            self.resolvers.clear();
+            self.opts = opts;
+        }
+
+        let dns_servers = dns_servers(config).await?;
+
+        let mut last_err = Error::NoDnsServers;
+        for dns in dns_servers {
+            match self
+                .resolve_custom_dns(hostname, dns, config.address_family)
+                .await
+            {
+                Ok(ip_addrs) => return permitted_addrs(config, hostname, ip_addrs),
+                Err(e) => {
+                    eprintln!("can't resolve {} using {}: {}", hostname, dns, e);
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    async fn resolve_custom_dns(
+        &mut self,
+        hostname: &str,
+        custom_dns: SocketAddr,
+        family: AddressFamily,
+    ) -> Result<Vec<IpAddr>> {
+        let opts = self.opts;
+        let (resolver, failures) = self
+            .resolvers
+            .entry(custom_dns)
+            .or_insert_with(|| (new_resolver(custom_dns, opts), 0));
+
+        let response = match resolver.lookup_ip(hostname).await {
+            Ok(response) => {
+                *failures = 0;
+                response
+            }
+            Err(e) => {
+                *failures += 1;
+                if *failures >= MAX_FAILURES {
+                    self.recreations += 1;
+                    eprintln!(
+                        "recreate resolver {} after {} failures ({} recreations total)",
+                        custom_dns, MAX_FAILURES, self.recreations
+                    );
+
+                    self.resolvers.remove(&custom_dns);
+                }
+
+                return Err(e.into());
+            }
+        };
+
+        let usable = usable_addrs(response.iter());
+        if usable.is_empty() {
+            return Err(Error::NoUsableAddress(hostname.to_string()));
+        }
+
+        let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) =
+            usable.into_iter().partition(|addr| addr.is_ipv4());
+
+        let ip_addrs = match family {
+            AddressFamily::Any => match v6.first() {
+                Some(IpAddr::V6(addr)) if ipv6_routable(*addr) => [v6, v4].concat(),
+                _ => [v4, v6].concat(),
+            },
+            AddressFamily::Ipv4 => v4,
+            AddressFamily::Ipv6 => v6,
+        };
+
+        if ip_addrs.is_empty() {
+            return Err(Error::NoHostname);
+        }
+
+        Ok(ip_addrs)
+    }
+}
+
+/// Returns the DNS servers to query in order, taking them from `resolv_conf`
+/// or `dns_servers` depending on `dns_source`.
+async fn dns_servers(config: &Config) -> Result<Vec<SocketAddr>> {
+    let mut dns_servers = match config.dns_source {
+        DnsSource::Static => config.dns_servers.clone(),
+        DnsSource::Dynamic => match read_resolv_conf(&config.resolv_conf).await {
+            Ok(dns_servers) if !dns_servers.is_empty() => dns_servers,
+            Ok(_) => {
+                eprintln!(
+                    "no nameservers in {}, using dns_servers",
+                    config.resolv_conf.display()
+                );
+                config.dns_servers.clone()
+            }
+            Err(e) => {
+                eprintln!(
+                    "can't read {}, using dns_servers: {}",
+                    config.resolv_conf.display(),
+                    e
+                );
+                config.dns_servers.clone()
+            }
+        },
+    };
+    if config.dns_fallback {
+        for fallback in FALLBACK_DNS_SERVERS {
+            dns_servers.push(fallback.parse()?);
+        }
+    }
+
+    Ok(dns_servers)
+}
+
+/// Splits the zone identifier off an IPv6 address like `fe80::1%ppp0`,
+/// returning the address and the scope id of the interface it names
+/// (or the numeric scope id). The scope id is 0 without a zone.
+pub fn split_zone(host: &str) -> Result<(&str, u32)> {
+    let Some((addr, zone)) = host.split_once('%') else {
+        return Ok((host, 0));
+    };
+
+    if addr.parse::<Ipv6Addr>().is_err() {
+        return Err(Error::InvalidServer(host.to_string()));
+    }
+
+    let scope_id = match zone.parse() {
+        Ok(scope_id) => scope_id,
+        Err(_) => nix::net::if_::if_nametoindex(zone)
+            .map_err(|_| Error::UnknownInterface(zone.to_string()))?,
+    };
+
+    Ok((addr, scope_id))
+}
+
+/// Returns the socket address of a server, applying the scope id
+/// of its zone to IPv6 addresses so that link-local ones are reachable.
+pub fn socket_addr(ip_addr: IpAddr, port: u16, scope_id: u32) -> SocketAddr {
+    match ip_addr {
+        IpAddr::V4(_) => SocketAddr::new(ip_addr, port),
+        IpAddr::V6(ip_addr) => SocketAddrV6::new(ip_addr, port, 0, scope_id).into(),
+    }
+}
+
+/// Returns the nameservers listed in a resolv.conf file.
+async fn read_resolv_conf(path: &Path) -> Result<Vec<SocketAddr>> {
+    let resolv_conf = fs::read_to_string(path).await?;
+
+    let mut dns_servers = Vec::new();
+    for addr in resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+    {
+        let (ip_addr, scope_id) = match split_zone(addr.trim()) {
+            Ok(split) => split,
+            Err(e) => {
+                eprintln!("ignore nameserver {}: {}", addr.trim(), e);
+                continue;
+            }
+        };
+
+        if let Ok(ip_addr) = ip_addr.parse() {
+            dns_servers.push(socket_addr(ip_addr, 53, scope_id));
+        }
+    }
+
+    Ok(dns_servers)
+}
+
+fn new_resolver(custom_dns: SocketAddr, (timeout, attempts): (u64, usize)) -> TokioAsyncResolver {
+    let mut cfg = ResolverConfig::new();
+
+    cfg.add_name_server(NameServerConfig::new(custom_dns, Protocol::Udp));
+
+    let mut opts = ResolverOpts::default();
+    opts.timeout = Duration::from_secs(timeout);
+    opts.attempts = attempts;
+
+    AsyncResolver::tokio(cfg, opts)
+}
+
+/// Removes the addresses outside of `allowed_networks` or inside of
+/// `denied_networks`, logging each of them.
+fn permitted_addrs(config: &Config, hostname: &str, ip_addrs: Vec<IpAddr>) -> Result<Vec<IpAddr>> {
+    let permitted: Vec<IpAddr> = ip_addrs
+        .into_iter()
+        .filter(|ip_addr| {
+            let permitted = config.permits(*ip_addr);
+            if !permitted {
+                eprintln!(
+                    "discard {} of {}, not permitted by network lists",
+                    ip_addr, hostname
+                );
+            }
+
+            permitted
+        })
+        .collect();
+
+    if permitted.is_empty() {
+        return Err(Error::NoUsableAddress(hostname.to_string()));
+    }
+
+    Ok(permitted)
+}
+
+/// Removes duplicates and addresses that can't belong to an NTP server
+/// (unspecified, loopback, link-local and multicast) from a DNS response,
+/// logging how many were discarded.
+fn usable_addrs(response: impl Iterator<Item = IpAddr>) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    let mut discarded = 0;
+
+    for addr in response {
+        if addr.is_unspecified()
+            || addr.is_loopback()
+            || is_link_local(addr)
+            || addr.is_multicast()
+            || addrs.contains(&addr)
+        {
+            discarded += 1;
+        } else {
+            addrs.push(addr);
+        }
+    }
+
+    if discarded > 0 {
+        eprintln!("discard {} bogus or duplicate dns results", discarded);
+    }
+
+    addrs
+}
+
+fn is_link_local(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_link_local(),
+        IpAddr::V6(addr) => addr.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Reports whether there is a route to the specified IPv6 address,
+/// i.e. whether IPv6 connectivity is currently up.
+fn ipv6_routable(addr: Ipv6Addr) -> bool {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect((addr, NTP_PORT)))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Writes a resolv.conf to a file unique to the test.
+    fn resolv_conf(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rsdsl_ntp-{}-{}.resolv.conf",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    #[tokio::test]
+    async fn read_nameservers() {
+        let path = resolv_conf(
+            "read",
+            "# generated by rsdsl_dhcp6\n\
+            search example.org\n\
+            nameserver 192.0.2.53\n\
+            \tnameserver   2001:db8::53  \n\
+            nameserver fe80::1%7\n\
+            nameserver fe80::2%rsdsl-nonexistent0\n\
+            nameserver not-an-address\n\
+            options edns0\n",
+        );
+
+        let dns_servers = read_resolv_conf(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let link_local = SocketAddrV6::new("fe80::1".parse().unwrap(), 53, 0, 7);
+        assert_eq!(
+            dns_servers,
+            [
+                addr("192.0.2.53:53"),
+                addr("[2001:db8::53]:53"),
+                link_local.into()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn static_dns_servers() {
+        let path = resolv_conf("static", "nameserver 192.0.2.53\n");
+        let config = Config {
+            dns_source: DnsSource::Static,
+            resolv_conf: path.clone(),
+            dns_servers: vec![addr("198.51.100.53:53")],
+            ..Default::default()
+        };
+
+        let dns_servers = dns_servers(&config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(dns_servers, config.dns_servers);
+    }
+
+    #[tokio::test]
+    async fn dynamic_dns_servers() {
+        let path = resolv_conf("dynamic", "nameserver 192.0.2.53\n");
+        let mut config = Config {
+            dns_source: DnsSource::Dynamic,
+            resolv_conf: path.clone(),
+            dns_servers: vec![addr("198.51.100.53:53")],
+            ..Default::default()
+        };
+
+        assert_eq!(dns_servers(&config).await.unwrap(), [addr("192.0.2.53:53")]);
+
+        // Fall back to the static servers without any nameservers.
+        std::fs::write(&path, "search example.org\n").unwrap();
+        assert_eq!(dns_servers(&config).await.unwrap(), config.dns_servers);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dns_servers(&config).await.unwrap(), config.dns_servers);
+
+        // The public resolvers come last.
+        config.dns_fallback = true;
+        let dns_servers = dns_servers(&config).await.unwrap();
+        assert_eq!(dns_servers[0], config.dns_servers[0]);
+        assert_eq!(dns_servers.len(), 1 + FALLBACK_DNS_SERVERS.len());
+    }
+
+    #[test]
+    fn permitted_addrs_mixed() {
+        let config = Config {
+            allowed_networks: vec!["192.0.2.0/24".to_string().try_into().unwrap()],
+            ..Default::default()
+        };
+        let resolved = vec![
+            ip("198.51.100.7"),
+            ip("192.0.2.1"),
+            ip("2001:db8::1"),
+            ip("192.0.2.2"),
+        ];
+
+        assert_eq!(
+            permitted_addrs(&config, "ntp.example", resolved).unwrap(),
+            [ip("192.0.2.1"), ip("192.0.2.2")]
+        );
+        assert!(matches!(
+            permitted_addrs(&config, "ntp.example", vec![ip("198.51.100.7")]),
+            Err(Error::NoUsableAddress(_))
+        ));
+    }
+
+    #[test]
+    fn usable_addrs_mixed() {
+        let response = [
+            "192.0.2.1",
+            "0.0.0.0",
+            "2001:db8::1",
+            "127.0.0.1",
+            "192.0.2.1",
+            "::",
+            "198.51.100.7",
+            "2001:db8::1",
+        ];
+
+        assert_eq!(
+            usable_addrs(response.into_iter().map(ip)),
+            [ip("192.0.2.1"), ip("2001:db8::1"), ip("198.51.100.7")]
+        );
+    }
+
+    #[test]
+    fn usable_addrs_duplicates_keep_order() {
+        let response = ["198.51.100.7", "192.0.2.1", "198.51.100.7", "192.0.2.1"];
+
+        assert_eq!(
+            usable_addrs(response.into_iter().map(ip)),
+            [ip("198.51.100.7"), ip("192.0.2.1")]
+        );
+    }
+
+    #[test]
+    fn usable_addrs_all_bogus() {
+        let response = [Ipv4Addr::UNSPECIFIED.into(), Ipv4Addr::LOCALHOST.into()];
+        assert!(usable_addrs(response.into_iter()).is_empty());
+    }
+
+    #[test]
+    fn usable_addrs_filtering() {
+        let discarded = [
+            // Unspecified
+            "0.0.0.0",
+            "::",
+            // Loopback
+            "127.0.0.1",
+            "127.1.2.3",
+            "::1",
+            // Link-local
+            "169.254.1.1",
+            "fe80::1",
+            "febf::1",
+            // Multicast
+            "224.0.1.1",
+            "239.255.255.250",
+            "ff02::101",
+            "ff0e::101",
+        ];
+        for addr in discarded {
+            assert!(usable_addrs([ip(addr)].into_iter()).is_empty(), "{}", addr);
+        }
+
+        let kept = [
+            "192.0.2.1",
+            "169.253.255.255",
+            "223.255.255.255",
+            "2001:db8::1",
+            "fec0::1",
+            "fe7f::1",
+        ];
+        for addr in kept {
+            assert_eq!(usable_addrs([ip(addr)].into_iter()), [ip(addr)], "{}", addr);
+        }
+    }
+
+    #[test]
+    fn split_zones() {
+        assert_eq!(split_zone("192.0.2.1").unwrap(), ("192.0.2.1", 0));
+        assert_eq!(split_zone("2001:db8::1").unwrap(), ("2001:db8::1", 0));
+        assert_eq!(split_zone("ntp.example").unwrap(), ("ntp.example", 0));
+        assert_eq!(split_zone("fe80::1%7").unwrap(), ("fe80::1", 7));
+
+        let lo = nix::net::if_::if_nametoindex("lo").unwrap();
+        assert_eq!(split_zone("fe80::1%lo").unwrap(), ("fe80::1", lo));
+
+        assert!(matches!(
+            split_zone("fe80::1%rsdsl-nonexistent0"),
+            Err(Error::UnknownInterface(zone)) if zone == "rsdsl-nonexistent0"
+        ));
+        assert!(matches!(
+            split_zone("192.0.2.1%lo"),
+            Err(Error::InvalidServer(_))
+        ));
+        assert!(matches!(
+            split_zone("ntp.example%lo"),
+            Err(Error::InvalidServer(_))
+        ));
+    }
+
+    #[test]
+    fn scoped_socket_addr() {
+        assert_eq!(socket_addr(ip("192.0.2.1"), 123, 7), addr("192.0.2.1:123"));
+
+        match socket_addr(ip("fe80::1"), 123, 7) {
+            SocketAddr::V6(addr) => {
+                assert_eq!(addr.scope_id(), 7);
+                assert_eq!(addr.port(), 123);
+            }
+            addr => panic!("{} isn't IPv6", addr),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::Result;
+
+use std::path::Path;
+
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use serde::Serialize;
+
+/// A single sync attempt as recorded in the event log.
+#[derive(Clone, Debug, Serialize)]
+pub struct Event<'a> {
+    pub timestamp: Option<i64>,
+    pub server: &'a str,
+    pub success: bool,
+    pub offset: Option<f64>,
+    pub delay: Option<f64>,
+    pub stratum: Option<u8>,
+    pub action: &'a str,
+    pub error: Option<String>,
+}
+
+/// Appends an event to a JSON lines file. Once the file reaches `max_size`
+/// bytes it is moved to `<path>.1`, replacing the previous generation.
+pub async fn append(path: &Path, max_size: u64, event: &Event<'_>) -> Result<()> {
+    if let Ok(metadata) = fs::metadata(path).await {
+        if metadata.len() >= max_size {
+            let mut rotated = path.as_os_str().to_owned();
+            rotated.push(".1");
+
+            fs::rename(path, rotated).await?;
+        }
+    }
+
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await?;
+    // The write may still be in progress once the file is dropped,
+    // the next rotation has to see its size.
+    file.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(server: &str) -> Event<'_> {
+        Event {
+            timestamp: Some(1_700_000_000),
+            server,
+            success: true,
+            offset: Some(0.001),
+            delay: Some(0.01),
+            stratum: Some(2),
+            action: "slew",
+            error: None,
+        }
+    }
+
+    async fn servers(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .await
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let event: serde_json::Value = serde_json::from_str(line).unwrap();
+                event["server"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rotate() {
+        let path = std::env::temp_dir().join(format!("rsdsl_ntp-{}.events", std::process::id()));
+        let rotated = path.with_extension("events.1");
+        let _ = fs::remove_file(&path).await;
+        let _ = fs::remove_file(&rotated).await;
+
+        append(&path, 1024, &event("a")).await.unwrap();
+        append(&path, 1024, &event("b")).await.unwrap();
+        assert_eq!(servers(&path).await, ["a", "b"]);
+
+        let size = fs::metadata(&path).await.unwrap().len();
+        append(&path, size, &event("c")).await.unwrap();
+        assert_eq!(servers(&rotated).await, ["a", "b"]);
+        assert_eq!(servers(&path).await, ["c"]);
+
+        // The next rotation replaces the previous generation.
+        append(&path, 1, &event("d")).await.unwrap();
+        assert_eq!(servers(&rotated).await, ["c"]);
+        assert_eq!(servers(&path).await, ["d"]);
+
+        fs::remove_file(&path).await.unwrap();
+        fs::remove_file(&rotated).await.unwrap();
+    }
+}
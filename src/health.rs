@@ -0,0 +1,250 @@
+use crate::now_unix;
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Every this many polls the least reliable server is tried first
+/// to give it a chance to recover.
+const REPROBE_INTERVAL: u64 = 8;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ServerStats {
+    pub successes: u64,
+    pub failures: u64,
+    /// Mean round-trip delay of all successful syncs in seconds.
+    pub avg_delay: Option<f64>,
+    pub consecutive_failures: u32,
+    /// Unix timestamp until which the server is avoided.
+    pub blacklisted_until: Option<i64>,
+}
+
+impl ServerStats {
+    /// Laplace-smoothed success rate, unknown servers score 0.5.
+    fn score(&self) -> f64 {
+        (self.successes + 1) as f64 / (self.successes + self.failures + 2) as f64
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    stats: BTreeMap<String, ServerStats>,
+    polls: u64,
+    preferred: Option<String>,
+}
+
+impl Health {
+    /// Returns the servers ordered by their historical reliability,
+    /// keeping the configured order for servers that score the same.
+    /// Blacklisted servers are left out unless all of them are blacklisted.
+    pub fn order(&mut self, servers: &[String]) -> Vec<String> {
+        let now = now_unix().unwrap_or_default();
+
+        let healthy: Vec<String> = servers
+            .iter()
+            .filter(|server| !self.is_blacklisted(server, now))
+            .cloned()
+            .collect();
+
+        let mut ordered = if healthy.is_empty() {
+            servers.to_vec()
+        } else {
+            healthy
+        };
+        ordered.sort_by(|a, b| self.score(b).total_cmp(&self.score(a)));
+
+        // Re-probing takes precedence over the preferred server.
+        let preferred = self.preferred.take();
+
+        self.polls += 1;
+        if self.polls % REPROBE_INTERVAL == 0 {
+            if let Some(worst) = ordered.pop() {
+                ordered.insert(0, worst);
+            }
+        } else if let Some(preferred) = preferred {
+            if let Some(i) = ordered.iter().position(|server| *server == preferred) {
+                let server = ordered.remove(i);
+                ordered.insert(0, server);
+            }
+        }
+
+        ordered
+    }
+
+    /// Makes the next call to `order` return `server` first
+    /// if it is still configured, not blacklisted
+    /// and the least reliable server isn't due for a re-probe.
+    pub fn prefer(&mut self, server: String) {
+        self.preferred = Some(server);
+    }
+
+    pub fn record_success(&mut self, server: &str, delay: f64) {
+        let stats = self.stats.entry(server.to_string()).or_default();
+
+        let avg_delay = stats.avg_delay.unwrap_or(0.0);
+        stats.avg_delay = Some(avg_delay + (delay - avg_delay) / (stats.successes + 1) as f64);
+        stats.successes += 1;
+        stats.consecutive_failures = 0;
+        stats.blacklisted_until = None;
+    }
+
+    /// Blacklists the server for `cooldown` seconds
+    /// once it has failed `threshold` times in a row.
+    pub fn record_failure(&mut self, server: &str, threshold: u32, cooldown: u64) {
+        let stats = self.stats.entry(server.to_string()).or_default();
+
+        stats.failures += 1;
+        stats.consecutive_failures += 1;
+
+        if threshold > 0 && stats.consecutive_failures >= threshold {
+            let until = now_unix()
+                .unwrap_or_default()
+                .saturating_add_unsigned(cooldown);
+            stats.blacklisted_until = Some(until);
+
+            eprintln!(
+                "blacklist {} for {}s after {} consecutive failures",
+                server, cooldown, stats.consecutive_failures
+            );
+        }
+    }
+
+    /// Avoids the server for `secs` seconds because it asked us to
+    /// (Kiss-o'-Death RATE), without counting it as a failure.
+    pub fn back_off(&mut self, server: &str, secs: u64) {
+        let stats = self.stats.entry(server.to_string()).or_default();
+
+        let until = now_unix().unwrap_or_default().saturating_add_unsigned(secs);
+        stats.blacklisted_until = Some(until);
+
+        eprintln!("back off from {} for {}s as requested", server, secs);
+    }
+
+    /// Returns how many times the servers have been ordered,
+    /// i.e. the number of the current poll.
+    pub fn polls(&self) -> usize {
+        self.polls as usize
+    }
+
+    pub fn stats(&self) -> &BTreeMap<String, ServerStats> {
+        &self.stats
+    }
+
+    fn is_blacklisted(&self, server: &str, now: i64) -> bool {
+        self.stats
+            .get(server)
+            .and_then(|stats| stats.blacklisted_until)
+            .is_some_and(|until| now < until)
+    }
+
+    fn score(&self, server: &str) -> f64 {
+        self.stats.get(server).map_or(0.5, ServerStats::score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn score() {
+        let mut health = Health::default();
+        assert_eq!(health.score("a"), 0.5);
+
+        health.record_success("a", 0.01);
+        health.record_success("a", 0.03);
+        health.record_failure("a", 0, 0);
+        assert_eq!(health.score("a"), 0.6);
+
+        let stats = &health.stats()["a"];
+        assert_eq!((stats.successes, stats.failures), (2, 1));
+        assert!((stats.avg_delay.unwrap() - 0.02).abs() < 1e-9);
+    }
+
+    #[test]
+    fn order_by_score() {
+        let mut health = Health::default();
+        let configured = servers(&["a", "b", "c"]);
+
+        // Servers without a history keep the configured order.
+        assert_eq!(health.order(&configured), configured);
+
+        health.record_failure("a", 0, 0);
+        health.record_success("c", 0.01);
+        assert_eq!(health.order(&configured), servers(&["c", "b", "a"]));
+    }
+
+    #[test]
+    fn reprobe() {
+        let mut health = Health::default();
+        let configured = servers(&["a", "b"]);
+
+        health.record_failure("b", 0, 0);
+        for poll in 1..=2 * REPROBE_INTERVAL {
+            let expected = if poll % REPROBE_INTERVAL == 0 {
+                ["b", "a"]
+            } else {
+                ["a", "b"]
+            };
+            assert_eq!(
+                health.order(&configured),
+                servers(&expected),
+                "poll {}",
+                poll
+            );
+        }
+    }
+
+    #[test]
+    fn blacklist_after_threshold() {
+        let mut health = Health::default();
+        let configured = servers(&["a", "b"]);
+
+        health.record_failure("a", 3, 60);
+        health.record_failure("a", 3, 60);
+        assert_eq!(health.order(&configured), servers(&["b", "a"]));
+
+        health.record_failure("a", 3, 60);
+        assert_eq!(health.order(&configured), servers(&["b"]));
+
+        // Nothing is left out if all servers are blacklisted.
+        health.record_failure("b", 1, 60);
+        assert_eq!(health.order(&configured), servers(&["b", "a"]));
+
+        // A success ends the blacklisting right away.
+        health.record_success("a", 0.01);
+        assert_eq!(health.order(&configured), servers(&["a"]));
+        assert_eq!(health.stats()["a"].consecutive_failures, 0);
+
+        // A threshold of zero never blacklists.
+        for _ in 0..10 {
+            health.record_failure("a", 0, 60);
+        }
+        assert_eq!(health.stats()["a"].blacklisted_until, None);
+    }
+
+    #[test]
+    fn prefer() {
+        let mut health = Health::default();
+        let configured = servers(&["a", "b", "c"]);
+
+        health.record_success("a", 0.01);
+        health.prefer("c".to_string());
+        assert_eq!(health.order(&configured), servers(&["c", "a", "b"]));
+
+        // The preference only applies to the next poll.
+        assert_eq!(health.order(&configured), servers(&["a", "b", "c"]));
+
+        // Servers that are no longer configured are ignored.
+        health.prefer("d".to_string());
+        assert_eq!(health.order(&configured), servers(&["a", "b", "c"]));
+
+        health.record_failure("c", 1, 60);
+        health.prefer("c".to_string());
+        assert_eq!(health.order(&configured), servers(&["a", "b"]));
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+/// A single sync attempt as kept in the in-memory history.
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub timestamp: Option<i64>,
+    pub server: String,
+    pub success: bool,
+    pub offset: Option<f64>,
+    pub delay: Option<f64>,
+}
+
+/// The most recent sync attempts, oldest first.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    entries: VecDeque<Entry>,
+}
+
+impl History {
+    /// Appends an entry, dropping the oldest ones
+    /// so that at most `capacity` entries are kept.
+    pub fn push(&mut self, entry: Entry, capacity: usize) {
+        self.entries.push_back(entry);
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn entries(&self) -> &VecDeque<Entry> {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(server: &str) -> Entry {
+        Entry {
+            timestamp: None,
+            server: server.to_string(),
+            success: true,
+            offset: Some(0.0),
+            delay: Some(0.01),
+        }
+    }
+
+    fn servers(history: &History) -> Vec<&str> {
+        history
+            .entries()
+            .iter()
+            .map(|entry| entry.server.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let mut history = History::default();
+
+        history.push(entry("a"), 2);
+        history.push(entry("b"), 2);
+        assert_eq!(servers(&history), ["a", "b"]);
+
+        history.push(entry("c"), 2);
+        assert_eq!(servers(&history), ["b", "c"]);
+
+        // A smaller size after a reload drops the excess at once.
+        history.push(entry("d"), 1);
+        assert_eq!(servers(&history), ["d"]);
+    }
+
+    #[test]
+    fn keep_nothing() {
+        let mut history = History::default();
+
+        history.push(entry("a"), 0);
+        assert!(history.entries().is_empty());
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use tokio::process::Command;
+
+/// Runs a shell command in the background, logging its exit status.
+/// The command is killed if it doesn't exit within `timeout`.
+/// `envs` are passed to the command as additional environment variables.
+pub fn spawn(command: String, envs: Vec<(&'static str, String)>, timeout: Duration) {
+    tokio::spawn(async move {
+        let mut child = match Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&command)
+            .envs(envs)
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("can't run hook {}: {}", command, e);
+                return;
+            }
+        };
+
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(Ok(status)) if status.success() => println!("run hook {}", command),
+            Ok(Ok(status)) => eprintln!("hook {} failed: {}", command, status),
+            Ok(Err(e)) => eprintln!("can't wait for hook {}: {}", command, e),
+            Err(_) => eprintln!("hook {} timed out, killing it", command),
+        }
+    });
+}
@@ -0,0 +1,311 @@
+use crate::clock::Clock;
+use crate::{now_unix, Error, Result};
+
+use std::time::Duration;
+
+use chrono::{Datelike, Utc};
+use tokio::task::JoinHandle;
+
+/// Number of consecutive replies that have to announce the same leap second.
+const CONFIRMATIONS: usize = 3;
+
+pub const LEAP_NONE: u8 = 0;
+pub const LEAP_INSERT: u8 = 1;
+pub const LEAP_DELETE: u8 = 2;
+
+/// Arms the kernel's leap second handling once servers
+/// consistently announce a leap second at the end of the current day.
+/// Alternatively the leap second is smeared over a window around midnight.
+#[derive(Debug, Default)]
+pub struct LeapTracker {
+    announced: u8,
+    confirmations: usize,
+    armed: Option<u8>,
+    smear: Option<Smear>,
+}
+
+/// A scheduled leap smear, running from `start` to `end` (Unix time).
+#[derive(Debug)]
+struct Smear {
+    start: i64,
+    end: i64,
+    /// Frequency adjustment in scaled ppm applied during the smear.
+    rate: libc::c_long,
+    task: JoinHandle<()>,
+}
+
+impl LeapTracker {
+    /// Processes the leap indicator of an accepted reply.
+    /// If `smear_window` is set the leap second is smeared over that many
+    /// seconds instead of being applied by the kernel.
+    pub fn update<C: Clock + Clone + Send + 'static>(
+        &mut self,
+        clock: &mut C,
+        leap: u8,
+        smear_window: Option<u64>,
+    ) -> Result<()> {
+        let now = now_unix()?;
+        if self.smear.as_ref().is_some_and(|smear| now > smear.end) {
+            self.smear = None;
+        }
+
+        if let Some(armed) = self.armed {
+            if clock.leap_state()? == libc::TIME_WAIT {
+                println!(
+                    "{} leap second",
+                    if armed == LEAP_INSERT {
+                        "insert"
+                    } else {
+                        "delete"
+                    }
+                );
+
+                clock.arm_leap(LEAP_NONE)?;
+                self.armed = None;
+            }
+        }
+
+        // 3 means unsynchronized, not a leap second.
+        if leap == self.announced && leap != 3 {
+            self.confirmations += 1;
+        } else {
+            self.announced = if leap == 3 { LEAP_NONE } else { leap };
+            self.confirmations = 1;
+        }
+
+        let confirmed = self.confirmations >= CONFIRMATIONS;
+
+        // The kernel applies the leap second at the end of the current UTC day,
+        // but servers announce it for the whole month.
+        if confirmed && self.announced != LEAP_NONE && is_last_day_of_month() {
+            if let Some(window) = smear_window {
+                if self.smear.is_none() {
+                    self.smear = Some(schedule_smear(clock.clone(), self.announced, window)?);
+                }
+
+                return Ok(());
+            }
+
+            // Stepping the clock may reset the kernel state, always rearm.
+            clock.arm_leap(self.announced)?;
+
+            if self.armed != Some(self.announced) {
+                println!(
+                    "schedule leap second {} at midnight utc",
+                    if self.announced == LEAP_INSERT {
+                        "insertion"
+                    } else {
+                        "deletion"
+                    }
+                );
+                self.armed = Some(self.announced);
+            }
+        } else if confirmed && self.announced == LEAP_NONE && self.armed.is_some() {
+            println!("cancel leap second");
+
+            clock.arm_leap(LEAP_NONE)?;
+            self.armed = None;
+        } else if confirmed && self.announced == LEAP_NONE {
+            // A smear that has already started has to finish
+            // to restore the frequency.
+            if self.smear.as_ref().is_some_and(|smear| now < smear.start) {
+                println!("cancel leap smear");
+
+                if let Some(smear) = self.smear.take() {
+                    smear.task.abort();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports whether a leap smear is in progress.
+    /// The clock must not be stepped during a smear.
+    pub fn smearing(&self) -> bool {
+        let now = now_unix().unwrap_or_default();
+        self.smear
+            .as_ref()
+            .is_some_and(|smear| (smear.start..=smear.end).contains(&now))
+    }
+
+    /// Returns the Unix start and end time of the scheduled or running smear.
+    pub fn smear_window(&self) -> Option<(i64, i64)> {
+        self.smear.as_ref().map(|smear| (smear.start, smear.end))
+    }
+
+    /// Cancels the smear, restoring the frequency if it is in progress,
+    /// so that the clock doesn't keep running at the wrong rate
+    /// after the daemon exits.
+    pub fn abort_smear(&mut self, clock: &mut impl Clock) -> Result<()> {
+        let smearing = self.smearing();
+
+        if let Some(smear) = self.smear.take() {
+            smear.task.abort();
+
+            if smearing {
+                clock.set_frequency(clock.frequency()? - smear.rate)?;
+                println!("abort leap smear");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Schedules slewing the clock by a second over `window` seconds
+/// centered on the coming midnight UTC.
+fn schedule_smear<C: Clock + Send + 'static>(mut clock: C, leap: u8, window: u64) -> Result<Smear> {
+    let midnight = Utc::now()
+        .date_naive()
+        .succ_opt()
+        .and_then(|tomorrow| tomorrow.and_hms_opt(0, 0, 0))
+        .map(|midnight| midnight.and_utc().timestamp())
+        .ok_or(Error::ImplausibleTime)?;
+
+    let half = i64::try_from(window / 2)?;
+    let start = midnight - half;
+    let end = midnight + half;
+
+    // Inserting a second means the clock has to run slower.
+    let rate = (1_000_000 << 16) / libc::c_long::try_from(window)?;
+    let rate = if leap == LEAP_INSERT { -rate } else { rate };
+
+    println!(
+        "schedule leap second {} as a {}s smear around midnight utc",
+        if leap == LEAP_INSERT {
+            "insertion"
+        } else {
+            "deletion"
+        },
+        window
+    );
+
+    let task = tokio::spawn(async move {
+        let wait = start.saturating_sub(now_unix().unwrap_or_default());
+        tokio::time::sleep(Duration::from_secs(wait.max(0).unsigned_abs())).await;
+
+        let freq = match clock.frequency() {
+            Ok(freq) => freq,
+            Err(e) => {
+                eprintln!("can't start leap smear: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = clock.set_frequency(freq + rate) {
+            eprintln!("can't start leap smear: {}", e);
+            return;
+        }
+
+        println!("start leap smear");
+
+        let remaining = end.saturating_sub(now_unix().unwrap_or_default());
+        tokio::time::sleep(Duration::from_secs(remaining.max(0).unsigned_abs())).await;
+
+        match clock.set_frequency(freq) {
+            Ok(_) => println!("end leap smear"),
+            Err(e) => eprintln!("can't end leap smear: {}", e),
+        }
+    });
+
+    Ok(Smear {
+        start,
+        end,
+        rate,
+        task,
+    })
+}
+
+fn is_last_day_of_month() -> bool {
+    Utc::now()
+        .date_naive()
+        .succ_opt()
+        .is_some_and(|tomorrow| tomorrow.day() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::mock::{Adjustment, MockClock};
+
+    #[test]
+    fn leap_second_applied() {
+        let mut clock = MockClock::default();
+        let mut leap = LeapTracker {
+            armed: Some(LEAP_INSERT),
+            ..Default::default()
+        };
+
+        // Still pending.
+        leap.update(&mut clock, LEAP_NONE, None).unwrap();
+        assert_eq!(leap.armed, Some(LEAP_INSERT));
+        assert!(clock.take_adjustments().is_empty());
+
+        // The kernel inserted the second, clear it before it's applied again.
+        clock.state().leap_state = libc::TIME_WAIT;
+        leap.update(&mut clock, LEAP_NONE, None).unwrap();
+        assert_eq!(leap.armed, None);
+        assert_eq!(clock.take_adjustments(), [Adjustment::ArmLeap(LEAP_NONE)]);
+    }
+
+    #[test]
+    fn leap_second_cancelled() {
+        let mut clock = MockClock::default();
+        let mut leap = LeapTracker {
+            armed: Some(LEAP_DELETE),
+            ..Default::default()
+        };
+
+        for _ in 1..CONFIRMATIONS {
+            leap.update(&mut clock, LEAP_NONE, None).unwrap();
+        }
+        assert!(clock.take_adjustments().is_empty());
+
+        leap.update(&mut clock, LEAP_NONE, None).unwrap();
+        assert_eq!(leap.armed, None);
+        assert_eq!(clock.take_adjustments(), [Adjustment::ArmLeap(LEAP_NONE)]);
+    }
+
+    #[tokio::test]
+    async fn abort_running_smear() {
+        let now = now_unix().unwrap();
+        let mut clock = MockClock::default();
+        clock.state().frequency = 100 - 42;
+
+        let mut leap = LeapTracker {
+            smear: Some(Smear {
+                start: now - 10,
+                end: now + 3600,
+                rate: -42,
+                task: tokio::spawn(std::future::pending()),
+            }),
+            ..Default::default()
+        };
+        assert!(leap.smearing());
+
+        leap.abort_smear(&mut clock).unwrap();
+        assert!(leap.smear_window().is_none());
+        assert_eq!(clock.take_adjustments(), [Adjustment::Frequency(100)]);
+    }
+
+    #[tokio::test]
+    async fn abort_scheduled_smear() {
+        let now = now_unix().unwrap();
+        let mut clock = MockClock::default();
+
+        let mut leap = LeapTracker {
+            smear: Some(Smear {
+                start: now + 3600,
+                end: now + 7200,
+                rate: -42,
+                task: tokio::spawn(std::future::pending()),
+            }),
+            ..Default::default()
+        };
+
+        // The frequency hasn't been changed yet.
+        leap.abort_smear(&mut clock).unwrap();
+        assert!(clock.take_adjustments().is_empty());
+    }
+}
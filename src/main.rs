@@ -1,32 +1,162 @@
-use std::net::{self, IpAddr, SocketAddr};
+mod asymmetry;
+mod auth;
+mod broadcast;
+mod client;
+mod clock;
+mod config;
+mod dhcp6;
+mod dns;
+mod events;
+mod health;
+mod history;
+mod hook;
+mod leap;
+mod nts;
+mod packet;
+mod poll;
+mod refclock;
+mod schedule;
+mod socks;
+mod status;
+mod suspend;
+mod systemd;
+
+use auth::Key;
+use client::Sample;
+use clock::{Clock, SystemClock};
+use config::{ClockKind, Config, Mode, PollMode, WatchdogAction};
+use dhcp6::{SystemProcesses, Tick};
+use dns::Resolver;
+use events::Event;
+use health::Health;
+use history::History;
+use leap::LeapTracker;
+use nts::Nts;
+use poll::AdaptivePoll;
+use schedule::Schedule;
+use status::Status;
+use suspend::SuspendDetector;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{self, Duration, SystemTime};
-use std::{array, io, num};
+use std::{array, env, fmt, io, num, process};
 
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::time::MissedTickBehavior;
+use tokio::sync::Notify;
+use tokio::time::Instant;
 
-use chrono::DateTime;
-use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
-use hickory_resolver::AsyncResolver;
-use nix::sys::time::TimeSpec;
-use nix::time::ClockId;
+use chrono::{DateTime, Utc};
 use rsdsl_netlinklib::Connection;
-use sysinfo::{ProcessExt, Signal, System, SystemExt};
 use thiserror::Error;
 
-const EPOCH_OFFSET: i64 = 2208988800;
 const LAST_UNIX_PATH: &str = "/data/ntp.last_unix";
-const NTP_SERVER: &str = "2.pool.ntp.org";
+const LAST_RFC3339_PATH: &str = "/data/ntp.last_rfc3339";
+/// Latest plausible saved time (9999-12-31T23:59:59Z).
+const MAX_UNIX: i64 = 253402300799;
+
+/// The build timestamp, used as the earliest possible time.
+static BUILD_TIME: OnceLock<i64> = OnceLock::new();
+/// Failures to persist state, each of them is only logged once
+/// until it works again so that a read-only /data doesn't flood the log.
+static SAVE_TIME: FailureLog = FailureLog::new();
+static SAVE_LAST_SERVER: FailureLog = FailureLog::new();
+static APPEND_EVENT: FailureLog = FailureLog::new();
+/// Whether the missing time state has been reported.
+static NO_TIME_STATE: AtomicBool = AtomicBool::new(false);
+const LAST_SERVER_PATH: &str = "/data/ntp.last_server";
+const LAST_INTERVAL_PATH: &str = "/data/ntp.last_interval";
 const NTP_PORT: u16 = 123;
-const DNS_SERVER: &str = "[2620:fe::fe]:53";
-const INITIAL_INTERVAL: Duration = Duration::from_secs(30);
-const INTERVAL: Duration = Duration::from_secs(3600);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const SUSPEND_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Backward steps larger than this many seconds are logged and counted.
+const BACKWARD_STEP_WARN: f64 = 0.001;
+/// Slews taking longer than this are logged as needing intervention.
+const MAX_SLEW_DURATION: Duration = Duration::from_secs(86400);
 
 #[derive(Debug, Error)]
 enum Error {
+    #[error("reply has a zero transmit timestamp")]
+    ZeroTransmitTime,
+    #[error("{0} asks to poll at most every {1}s")]
+    RateLimited(SocketAddr, u64),
+    #[error("{0} refuses service ({1})")]
+    KissOfDeath(SocketAddr, String),
+    #[error("server has never synchronized")]
+    NoReference,
+    #[error("server last synchronized {0:.0}s ago")]
+    StaleReference(f64),
+    #[error("timestamp is out of the representable range")]
+    ImplausibleTime,
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("no ntp servers configured")]
+    NoServers,
+    #[error("invalid server address {0}")]
+    InvalidServer(String),
+    #[error("unknown interface {0} in zone id")]
+    UnknownInterface(String),
+    #[error("can't reach {1} from bind address {0}")]
+    BindFamily(SocketAddr, SocketAddr),
+    #[error("no reply from {0}")]
+    NoReply(SocketAddr),
+    #[error("can't send request to {0}: {1}")]
+    SendFailed(SocketAddr, io::Error),
+    #[error("malformed reply from {0}: {1}")]
+    MalformedReply(SocketAddr, Box<Error>),
+    #[error("reply from {0} doesn't match any request, it may be spoofed or stale")]
+    Spoofed(SocketAddr),
+    #[error("no ntp server is reachable")]
+    NoReachableServer,
+    #[error("sync failed {0} times in a row")]
+    TooManyFailures(u32),
+    #[error("no successful sync for {0}s")]
+    Watchdog(u64),
+    #[error("not synchronized yet")]
+    NotSynced,
+    #[error("last sync was {0}s ago")]
+    StaleSync(i64),
+    #[error("no dns servers configured")]
+    NoDnsServers,
     #[error("can't find ntp server hostname")]
     NoHostname,
+    #[error("{0} doesn't resolve to any usable address")]
+    NoUsableAddress(String),
+    #[error("packet too short ({0} bytes)")]
+    ShortPacket(usize),
+    #[error("unexpected packet mode {0}")]
+    UnexpectedMode(u8),
+    #[error("malformed key: {0}")]
+    MalformedKey(String),
+    #[error("unsupported key algorithm {0} (supported: MD5, SHA1)")]
+    UnsupportedAlgorithm(String),
+    #[error("key {0} not found")]
+    KeyNotFound(u32),
+    #[error("reply is not authenticated")]
+    Unauthenticated,
+    #[error("reply authentication failed")]
+    BadMac,
+    #[error("round-trip delay of {0:.3}s exceeds max_delay")]
+    ExcessiveDelay(f64),
+    #[error("correction of {0:+.3}s exceeds the panic threshold")]
+    Panic(f64),
+    #[error("only {1} of {2} other servers agree with {0}")]
+    Disagreement(String, usize, usize),
+    #[error("refclock error: {0}")]
+    Refclock(String),
+    #[error("socks5 error: {0}")]
+    Socks(String),
+    #[error("nts-ke failed: {0}")]
+    NtsKeyExchange(String),
+    #[error("no nts cookies left")]
+    NtsNoCookies,
+    #[error("nts reply authentication failed")]
+    NtsUnauthenticated,
 
     #[error("io error: {0}")]
     Io(#[from] io::Error),
@@ -34,6 +164,8 @@ enum Error {
     ParseAddr(#[from] net::AddrParseError),
     #[error("system time monotonicity error: {0}")]
     SystemTime(#[from] time::SystemTimeError),
+    #[error("can't parse integer: {0}")]
+    ParseInt(#[from] num::ParseIntError),
     #[error("integer doesn't fit: {0}")]
     TryFromInt(#[from] num::TryFromIntError),
     #[error("slice length does not equal array length: {0}")]
@@ -43,12 +175,20 @@ enum Error {
     ChronoParse(#[from] chrono::ParseError),
     #[error("nix errno: {0}")]
     NixErrno(#[from] nix::errno::Errno),
-    #[error("ntp error: {0}")]
-    Ntp(#[from] ntp::errors::Error),
+    #[error("serde_json error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
     #[error("hickory_resolver resolve error: {0}")]
     HickoryResolve(#[from] hickory_resolver::error::ResolveError),
+    #[error("request timed out: {0}")]
+    Timeout(#[from] tokio::time::error::Elapsed),
     #[error("netlinklib error: {0}")]
     Netlinklib(#[from] rsdsl_netlinklib::Error),
+    #[error("rustls error: {0}")]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+    #[error("invalid tls server name: {0}")]
+    InvalidDnsName(#[from] tokio_rustls::rustls::client::InvalidDnsNameError),
+    #[error("getrandom error: {0}")]
+    Getrandom(#[from] getrandom::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -57,107 +197,1818 @@ type Result<T> = std::result::Result<T, Error>;
 async fn main() -> Result<()> {
     println!("init");
 
-    match disk_to_sys().await {
-        Ok(_) => println!("load system time"),
-        Err(e) => eprintln!("can't load system time: {}", e),
+    let (mut config, mut key) = load_config().await?;
+
+    if env::args().any(|arg| arg == "--print-config") {
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
     }
 
-    println!("wait for pppoe");
+    if env::args().any(|arg| arg == "--health") {
+        return status::check_health(config.health_max_age).await;
+    }
+
+    match parse_build_time() {
+        Ok(t) => {
+            let _ = BUILD_TIME.set(t);
+        }
+        Err(e) => {
+            eprintln!(
+                "invalid build timestamp {:?}, the build is broken: {}",
+                env!("SOURCE_TIMESTAMP"),
+                e
+            );
+            return Err(e);
+        }
+    }
+
+    if env::args().any(|arg| arg == "--check") {
+        return check(
+            &config,
+            key.as_ref(),
+            &mut Resolver::default(),
+            &mut Nts::default(),
+        )
+        .await;
+    }
+
+    let mut clock = SystemClock;
+
+    // Another clock source is in charge in monitor mode.
+    if config.mode == Mode::Monitor {
+        println!("monitor mode, never adjust the clock");
+    } else {
+        match disk_to_sys(&mut clock, &config).await {
+            Ok(_) => println!("load system time"),
+            Err(e) => eprintln!("can't load system time: {}", e),
+        }
+
+        if config.clock_id == ClockKind::Tai {
+            match clock.set_tai_offset(config.tai_offset) {
+                Ok(_) => println!("set tai offset to {}s", config.tai_offset),
+                Err(e) => eprintln!("can't set tai offset: {}", e),
+            }
+        }
+    }
+
+    if config.static_offset_ms != 0.0 {
+        println!(
+            "apply static offset of {:+} ms to all measurements",
+            config.static_offset_ms
+        );
+    }
+
+    if config.mode == Mode::Oneshot || env::args().any(|arg| arg == "--oneshot") {
+        return oneshot(&config, key.as_ref()).await;
+    }
+
+    let status = Arc::new(Mutex::new(Status {
+        poll_interval: config.poll_interval(config.initial_interval).as_secs(),
+        config: Some(config.clone()),
+        ..Default::default()
+    }));
+
+    let resync = Arc::new(Notify::new());
+
+    let socket_status = status.clone();
+    let socket_resync = resync.clone();
+    let socket_path = config.status_socket.clone();
+    let sync_interval = Duration::from_secs(config.sync_request_interval);
+    tokio::spawn(async move {
+        if let Err(e) =
+            status::serve(&socket_path, socket_status, socket_resync, sync_interval).await
+        {
+            eprintln!("can't serve status socket: {}", e);
+        }
+    });
+
+    // Readiness doesn't depend on the link, systemd would otherwise
+    // time out the start while the modem is still training.
+    systemd::notify("READY=1\nSTATUS=waiting for link");
 
     let conn = Connection::new().await?;
-    conn.link_wait_up("ppp0".into()).await?;
+    wait_for_link(&conn, config.link_timeout, config.link_settle).await?;
+
+    // Requests of many devices would otherwise cluster on the whole second
+    // as all intervals are whole seconds.
+    let phase = Duration::from_millis(random_below(1000));
+    let mut schedule = Schedule::new(config.poll_interval(config.initial_interval), phase);
+    println!("poll at a phase of {}ms", phase.as_millis());
+
+    // Spread out the polls of routers that boot at the same time,
+    // e.g. after a power outage.
+    if config.startup_jitter > 0 {
+        let jitter = Duration::from_secs(random_below(config.startup_jitter + 1));
+        schedule.delay(jitter);
 
-    let mut resync = tokio::time::interval(INITIAL_INTERVAL);
+        println!("delay first poll by {}s", jitter.as_secs());
+    }
     let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+
+    let mut resolver = Resolver::default();
+    let mut nts = Nts::default();
+    let mut health = Health::default();
+    let mut history = History::default();
+
+    let mut last_server = match load_last_server().await {
+        Ok(last_server) => last_server,
+        Err(e) => {
+            eprintln!("can't load last server: {}", e);
+            None
+        }
+    };
+    if let Some(server) = &last_server {
+        println!("try last server {} first", server);
+        health.prefer(server.clone());
+    }
+    let mut adaptive = AdaptivePoll::new(config.interval);
+    if config.poll_mode == PollMode::Adaptive {
+        match load_last_interval(LAST_INTERVAL_PATH).await {
+            Ok(Some(interval)) => {
+                // The first sync still happens at initial_interval.
+                let interval = interval.clamp(config.min_poll, config.max_poll);
+                println!("resume adaptive poll interval of {}s", interval);
+
+                adaptive = AdaptivePoll::new(interval);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("can't load adaptive poll interval: {}", e),
+        }
+    }
+    let mut leap = LeapTracker::default();
 
-    resync.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut suspend_check = tokio::time::interval(SUSPEND_CHECK_INTERVAL);
+    let mut suspend = SuspendDetector::new()?;
 
+    let mut server_poll = 0;
+    let mut burst_pending = true;
+    let mut last_success = Instant::now();
     let mut first_sync = true;
-    loop {
+    let dhcp6 = dhcp6::spawn(SystemProcesses);
+    let mut accept_panic = false;
+
+    systemd::notify("STATUS=waiting for the first sync");
+    // Fatal errors break out of the loop as well so that the time
+    // is still saved and a leap smear undone before exiting.
+    let result = loop {
         tokio::select! {
-            _ = resync.tick() => match sync_time(NTP_SERVER).await {
-                Ok(_) => {
-                    if first_sync {
-                        resync = tokio::time::interval(INTERVAL);
-                        resync.reset();
-                        resync.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            _ = schedule.wait() => {
+                schedule.advance();
+                status.lock().unwrap().next_poll = schedule.next_unix();
+
+                if leap.smearing() {
+                    println!("skip sync during leap smear");
+                    continue;
+                }
+
+                status.lock().unwrap().syncing = true;
+
+                let monitor = config.mode == Mode::Monitor
+                    && !(config.monitor_initial_sync && first_sync);
+                // Large offsets are worth reporting rather than refusing
+                // if the clock isn't going to be corrected anyway.
+                let mode = SyncMode {
+                    burst: if burst_pending { config.burst } else { 1 },
+                    slew_only: config.never_step_after_first && !first_sync,
+                    panic_threshold: (config.panic_threshold > 0 && !first_sync && !accept_panic && !monitor)
+                        .then_some(config.panic_threshold as f64),
+                    monitor,
+                };
+                match sync_with_retries(&mut clock, &config, key.as_ref(), &mut resolver, &mut nts, &mut health, &mut history, mode).await {
+                    Ok(outcome) => {
+                        {
+                            let mut status = status.lock().unwrap();
+
+                            status.synced = true;
+                            status.last_sync = now_unix().ok();
+                            status.server = Some(outcome.server.clone());
+                            status.address = Some(outcome.address);
+                            status.offset = Some(outcome.offset);
+                            status.delay = Some(outcome.delay);
+                            status.action = Some(outcome.action);
+                            status.stratum = Some(outcome.stratum);
+                            status.reference_id = Some(outcome.reference_id.clone());
+                            status.reference_age = Some(outcome.reference_age);
+                            status.consecutive_failures = 0;
+                            status.last_error = None;
+                            status.panic = false;
+                            status.agreeing = outcome.agreeing;
+                            if outcome.action == "step" && outcome.correction < -BACKWARD_STEP_WARN {
+                                status.backward_steps += 1;
+                            }
+                            status.era_rollovers += outcome.eras.unsigned_abs();
+
+                            if first_sync {
+                                status.cold_start = outcome.cold_start;
+                            }
+                        }
+
+                        if last_server.as_ref() != Some(&outcome.server) {
+                            let result = write_atomic(LAST_SERVER_PATH, outcome.server.as_bytes()).await;
+                            SAVE_LAST_SERVER.report(format_args!("save last server to {}", LAST_SERVER_PATH), &result);
+                            if result.is_ok() {
+                                last_server = Some(outcome.server.clone());
+                            }
+                        }
+
+                        if config.verify {
+                            verify_sync(&mut clock, &config, key.as_ref(), &mut resolver, &mut nts, &outcome).await;
+                        }
 
-                        for dhcp6 in System::new_all().processes_by_exact_name("rsdsl_dhcp6") {
-                            dhcp6.kill_with(Signal::User2);
+                        if config.asymmetry_check {
+                            let bias = check_asymmetry(&config, key.as_ref(), &mut resolver, &mut nts, &outcome.server).await;
+                            status.lock().unwrap().asymmetry_bias = bias;
                         }
 
-                        first_sync = false;
+                        if config.leap_seconds && !mode.monitor {
+                            let smear_window = config.leap_smear.then_some(config.leap_smear_window);
+                            if let Err(e) = leap.update(&mut clock, outcome.leap, smear_window) {
+                                eprintln!("can't handle leap second: {}", e);
+                            }
+
+                            status.lock().unwrap().leap_smear = leap.smear_window();
+                        }
+
+                        if config.poll_mode == PollMode::Adaptive {
+                            let interval = adaptive.interval();
+                            adaptive.update(outcome.offset, config.min_poll, config.max_poll);
+
+                            if adaptive.interval() != interval {
+                                if let Err(e) = save_last_interval(LAST_INTERVAL_PATH, adaptive.interval()).await {
+                                    eprintln!("can't save adaptive poll interval: {}", e);
+                                }
+                            }
+                        }
+
+                        server_poll = outcome.poll;
+                        burst_pending = false;
+                        accept_panic = false;
+                        last_success = Instant::now();
+
+                        let period = poll_period(&config, false, &adaptive, server_poll);
+                        if schedule.set_period(period) {
+                            println!("poll every {}s", period.as_secs());
+                        }
+
+                        let hook_envs = vec![
+                            ("NTP_SERVER", outcome.server.clone()),
+                            ("NTP_OFFSET", outcome.offset.to_string()),
+                        ];
+                        let hook_timeout = Duration::from_secs(config.hook_timeout);
+
+                        if config.notify_dhcp6 {
+                            let _ = dhcp6.send(Tick::Synced(config.notify_confirmations));
+                        }
+
+                        if first_sync {
+                            if let Some(command) = &config.on_first_sync {
+                                hook::spawn(command.clone(), hook_envs.clone(), hook_timeout);
+                            }
+
+                            first_sync = false;
+                        }
+
+                        if let Some(command) = &config.on_sync {
+                            hook::spawn(command.clone(), hook_envs, hook_timeout);
+                        }
                     }
+                    Err(e) => {
+                        let _ = dhcp6.send(Tick::Failed);
+
+                        let failures = {
+                            let mut status = status.lock().unwrap();
+                            status.consecutive_failures += 1;
+                            status.last_error = Some(e.to_string());
+                            if let Error::Panic(_) = e {
+                                status.panic = true;
+                                status.panics += 1;
+                            }
+                            if let Error::Disagreement(..) = e {
+                                status.disagreements += 1;
+                            }
+                            status.consecutive_failures
+                        };
+                        eprintln!("can't synchronize system time: {}", e);
+                        if let Error::Panic(_) = e {
+                            eprintln!(
+                                "PANIC: refuse to correct the clock, \
+                                send SIGUSR2 to accept the next correction"
+                            );
+                        }
+
+                        // Congestion tends to be short-lived, don't wait a full interval.
+                        if let Error::ExcessiveDelay(_) = e {
+                            schedule.retry_in(config.poll_interval(config.initial_interval));
+                            status.lock().unwrap().next_poll = schedule.next_unix();
+                        }
+
+                        // Don't favor a server that didn't help on the next start.
+                        if last_server.take().is_some() {
+                            let result = fs::remove_file(LAST_SERVER_PATH).await.map_err(Error::from);
+                            SAVE_LAST_SERVER.report(format_args!("forget last server in {}", LAST_SERVER_PATH), &result);
+                        }
+
+                        if config.max_consecutive_failures > 0
+                            && failures >= config.max_consecutive_failures
+                        {
+                            eprintln!("giving up after {} consecutive failures", failures);
+                            break Err(Error::TooManyFailures(failures));
+                        }
+
+                        let unsynced = last_success.elapsed();
+                        if config.watchdog_timeout > 0
+                            && unsynced.as_secs() >= config.watchdog_timeout
+                        {
+                            eprintln!("WATCHDOG: no successful sync for {}s", unsynced.as_secs());
+
+                            match config.watchdog_action {
+                                WatchdogAction::Exit => {
+                                    break Err(Error::Watchdog(unsynced.as_secs()));
+                                }
+                                WatchdogAction::Restart => {
+                                    if let Err(e) = wait_for_link(&conn, config.link_timeout, config.link_settle).await {
+                                        break Err(e);
+                                    }
+
+                                    resolver = Resolver::default();
+                                    nts = Nts::default();
+                                    health = Health::default();
+                                    burst_pending = true;
+                                    last_success = Instant::now();
+                                    schedule.poll_now();
+
+                                    println!("restart sync after watchdog");
+                                }
+                            }
+                        }
+                    }
+                }
+
+                {
+                    let mut status = status.lock().unwrap();
+
+                    status.syncing = false;
+                    status.servers = health.stats().clone();
+                    status.history = history.entries().clone();
+                    status.next_poll = schedule.next_unix();
+                    status.poll_interval = schedule.period().as_secs();
                 }
-                Err(e) => eprintln!("can't synchronize system time: {}", e),
+
+                let mut snapshot = status.lock().unwrap().clone();
+                if let Err(e) = snapshot.write().await {
+                    eprintln!("can't write status: {}", e);
+                }
+                systemd::notify(&format!("STATUS={}", snapshot.summary()));
+            }
+            _ = suspend_check.tick() => match suspend.check() {
+                Ok(Some(slept)) => {
+                    println!("resume after {}s of suspend, poll now", slept.as_secs());
+
+                    schedule.poll_now();
+                    burst_pending = true;
+                    status.lock().unwrap().next_poll = schedule.next_unix();
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("can't check for suspend: {}", e),
             },
-            _ = sigterm.recv() => {
-                sysnow_to_disk().await?;
+            // Requests arriving during a sync are coalesced into a single
+            // poll afterwards as the sync runs inside of this loop.
+            _ = sigusr1.recv() => {
+                println!("sync requested via sigusr1");
+
+                schedule.poll_now();
+            }
+            _ = sigusr2.recv() => {
+                println!("accept the next correction regardless of the panic threshold");
+
+                accept_panic = true;
+                schedule.poll_now();
+            }
+            _ = resync.notified() => schedule.poll_now(),
+            _ = sighup.recv() => match load_config().await {
+                Ok((new_config, new_key)) => {
+                    let period = poll_period(&new_config, first_sync, &adaptive, server_poll);
+                    {
+                        let mut status = status.lock().unwrap();
+                        if schedule.set_period(period) {
+                            status.next_poll = schedule.next_unix();
+                            status.poll_interval = period.as_secs();
+                        }
+                        status.config = Some(new_config.clone());
+                    }
+
+                    config = new_config;
+                    key = new_key;
+
+                    println!("reload config");
+                }
+                Err(e) => eprintln!("can't reload config, keeping previous: {}", e),
+            },
+            _ = sigterm.recv() => break Ok(()),
+            _ = sigint.recv() => break Ok(()),
+        }
+    };
+
+    if let Err(e) = leap.abort_smear(&mut clock) {
+        eprintln!("can't abort leap smear: {}", e);
+    }
 
-                println!("save system time");
+    // The saved time is never loaded in monitor mode.
+    if config.mode == Mode::Monitor {
+        return result;
+    }
+
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, sysnow_to_disk()).await {
+        Ok(Ok(_)) => println!("save system time"),
+        Ok(Err(e)) => eprintln!("can't save system time: {}", e),
+        Err(_) => {
+            // Dropping the runtime would wait for the hung write.
+            eprintln!("can't save system time: timed out");
+            process::exit(1);
+        }
+    }
+
+    result
+}
+
+/// Waits for the link and synchronizes the clock once,
+/// failing if no server could be used.
+async fn oneshot(config: &Config, key: Option<&Key>) -> Result<()> {
+    let conn = Connection::new().await?;
+    wait_for_link(&conn, config.link_timeout, config.link_settle).await?;
+
+    let outcome = sync_with_retries(
+        &mut SystemClock,
+        config,
+        key,
+        &mut Resolver::default(),
+        &mut Nts::default(),
+        &mut Health::default(),
+        &mut History::default(),
+        SyncMode {
+            burst: config.burst,
+            slew_only: false,
+            panic_threshold: None,
+            monitor: false,
+        },
+    )
+    .await?;
+
+    println!("synchronized using {}", outcome.server);
+    Ok(())
+}
+
+/// Waits for the WAN link to come up, giving up after `link_timeout` seconds
+/// unless it is zero. Once it is up, routing and address assignment
+/// are given `link_settle` seconds to catch up before the first request.
+async fn wait_for_link(conn: &Connection, link_timeout: u64, link_settle: u64) -> Result<()> {
+    println!("wait for pppoe");
+
+    if link_timeout == 0 {
+        conn.link_wait_up("ppp0".into()).await?;
+    } else {
+        let wait = conn.link_wait_up("ppp0".into());
+        match tokio::time::timeout(Duration::from_secs(link_timeout), wait).await {
+            Ok(result) => result?,
+            Err(_) => {
+                eprintln!("pppoe not up after {}s, polling anyway", link_timeout);
                 return Ok(());
             }
         }
     }
+
+    if link_settle > 0 {
+        println!("pppoe up, wait {}s for it to settle", link_settle);
+        tokio::time::sleep(Duration::from_secs(link_settle)).await;
+    }
+
+    Ok(())
 }
 
-async fn last_time_unix() -> Option<i64> {
-    Some(i64::from_be_bytes(
-        fs::read(LAST_UNIX_PATH).await.ok()?[..8].try_into().ok()?,
-    ))
+/// Formats a duration in seconds in the most readable unit, e.g. `48.1 ms`.
+fn format_secs(secs: f64) -> String {
+    let abs = secs.abs();
+    if abs >= 1.0 {
+        format!("{:.3} s", secs)
+    } else if abs >= 1e-3 {
+        format!("{:.1} ms", secs * 1e3)
+    } else if abs >= 1e-6 {
+        format!("{:.1} µs", secs * 1e6)
+    } else {
+        format!("{:.0} ns", secs * 1e9)
+    }
 }
 
-async fn sysnow_to_disk() -> Result<()> {
-    let t: i64 = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)?
+/// Formats an offset in seconds like [`format_secs`] but always signed,
+/// e.g. `+12.4 ms`.
+fn format_offset(secs: f64) -> String {
+    let sign = if secs.is_sign_negative() { '-' } else { '+' };
+    format!("{}{}", sign, format_secs(secs.abs()))
+}
+
+/// Returns a random number in `0..max`. Not suitable for cryptography.
+fn random_below(max: u64) -> u64 {
+    RandomState::new().build_hasher().finish() % max
+}
+
+/// Chooses the poll period, never polling faster than the last server
+/// asked for (`server_poll` seconds) after the first sync.
+fn poll_period(
+    config: &Config,
+    first_sync: bool,
+    adaptive: &AdaptivePoll,
+    server_poll: u64,
+) -> Duration {
+    if first_sync {
+        return config.poll_interval(config.initial_interval);
+    }
+
+    let interval = match config.poll_mode {
+        PollMode::Fixed => config.interval,
+        PollMode::Adaptive => adaptive.interval(),
+    };
+
+    config.poll_interval(interval.max(server_poll))
+}
+
+async fn load_config() -> Result<(Config, Option<Key>)> {
+    let config = Config::load().await?;
+    let key = match config.key_id {
+        Some(id) => Some(Key::load(&config.keys, id).await?),
+        None => None,
+    };
+
+    Ok((config, key))
+}
+
+async fn last_time_unix() -> Result<Option<i64>> {
+    match fs::read(LAST_UNIX_PATH).await {
+        Ok(buf) => {
+            let t = i64::from_be_bytes(buf.as_slice().try_into()?);
+
+            // Guards the era arithmetic against a corrupted file.
+            if !(0..=MAX_UNIX).contains(&t) {
+                return Err(Error::ImplausibleTime);
+            }
+
+            Ok(Some(t))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn load_last_interval(path: &str) -> Result<Option<u64>> {
+    match fs::read_to_string(path).await {
+        Ok(interval) => Ok(Some(interval.trim().parse()?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn save_last_interval(path: &str, interval: u64) -> Result<()> {
+    write_atomic(path, interval.to_string().as_bytes()).await
+}
+
+async fn load_last_server() -> Result<Option<String>> {
+    match fs::read_to_string(LAST_SERVER_PATH).await {
+        Ok(server) => Ok(Some(server.trim().to_string()).filter(|server| !server.is_empty())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Parses the build timestamp, which is validated on startup.
+fn parse_build_time() -> Result<i64> {
+    let t = DateTime::parse_from_rfc3339(env!("SOURCE_TIMESTAMP"))?.timestamp();
+    if !(0..=MAX_UNIX).contains(&t) {
+        return Err(Error::ImplausibleTime);
+    }
+
+    Ok(t)
+}
+
+fn build_time_unix() -> i64 {
+    *BUILD_TIME.get_or_init(|| parse_build_time().expect("build timestamp is validated on startup"))
+}
+
+/// Returns the earliest plausible time, the latest of the build time,
+/// the saved time and `min_time`.
+fn time_floor(config: &Config, last: Option<i64>) -> i64 {
+    [last, config.min_time]
+        .into_iter()
+        .flatten()
+        .fold(build_time_unix(), i64::max)
+}
+
+fn now_unix() -> Result<i64> {
+    unix_time(SystemTime::now())
+}
+
+/// Converts a system time to whole seconds since the Unix epoch,
+/// failing for times before the epoch.
+fn unix_time(time: SystemTime) -> Result<i64> {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::ImplausibleTime)?
         .as_secs()
-        .try_into()?;
-    fs::write(LAST_UNIX_PATH, t.to_be_bytes()).await?;
+        .try_into()
+        .map_err(|_| Error::ImplausibleTime)
+}
+
+async fn sysnow_to_disk() -> Result<()> {
+    save_time(now_unix()?).await
+}
+
+/// Saves a Unix timestamp for `disk_to_sys`
+/// and as RFC 3339 for humans, the latter on a best effort basis.
+/// A failure is only logged once until saving works again.
+async fn save_time(t: i64) -> Result<()> {
+    let result = write_time(t).await;
+    SAVE_TIME.report(format_args!("save time to {}", LAST_UNIX_PATH), &result);
+
+    result
+}
+
+async fn write_time(t: i64) -> Result<()> {
+    write_atomic(LAST_UNIX_PATH, &t.to_be_bytes()).await?;
+
+    let rfc3339 = match DateTime::<Utc>::from_timestamp(t, 0) {
+        Some(datetime) => datetime.to_rfc3339(),
+        None => return Err(Error::ImplausibleTime),
+    };
+    if let Err(e) = write_atomic(LAST_RFC3339_PATH, format!("{}\n", rfc3339).as_bytes()).await {
+        eprintln!("can't save rfc3339 time: {}", e);
+    }
 
     Ok(())
 }
 
-async fn disk_to_sys() -> Result<()> {
-    let t = i64::from_be_bytes(fs::read(LAST_UNIX_PATH).await?[..8].try_into()?);
-    let timespec = TimeSpec::new(t, 0);
+/// Writes to a temporary file and renames it to `path` so that readers
+/// (e.g. the next boot) never see a partially written file.
+async fn write_atomic(path: &str, contents: &[u8]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
 
-    nix::time::clock_settime(ClockId::CLOCK_REALTIME, timespec)?;
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
 
+    fs::rename(&tmp, path).await?;
     Ok(())
 }
 
-async fn sync_time(server: &str) -> Result<()> {
-    let last = last_time_unix()
-        .await
-        .unwrap_or(DateTime::parse_from_rfc3339(env!("SOURCE_TIMESTAMP"))?.timestamp());
+/// Logs the first failure of a recurring operation and when it works again.
+struct FailureLog(AtomicBool);
 
-    let dns = DNS_SERVER.parse()?;
-    let server_resolved = SocketAddr::new(resolve_custom_dns(server, dns).await?, NTP_PORT);
+impl FailureLog {
+    const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
 
-    let time = ntp::request(server_resolved)?.transmit_time;
+    /// Reports the result of the operation described by `what`,
+    /// e.g. `save time`.
+    fn report<T>(&self, what: fmt::Arguments, result: &Result<T>) {
+        match result {
+            Ok(_) => {
+                if self.0.swap(false, Ordering::Relaxed) {
+                    println!("{} again", what);
+                }
+            }
+            Err(e) => {
+                if !self.0.swap(true, Ordering::Relaxed) {
+                    eprintln!(
+                        "ERROR: can't {}, suppressing further errors until it works again: {}",
+                        what, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Sets the clock to the saved time or, on the first run,
+/// advances it to the time floor if it is earlier.
+async fn disk_to_sys(clock: &mut impl Clock, config: &Config) -> Result<()> {
+    let last = last_time_unix().await?;
+    let floor = time_floor(config, last);
 
-    let mut t = time.sec as i64 - EPOCH_OFFSET;
-    while t < last {
-        t += 2_i64.pow(32); // NTP era duration.
+    let t = match last {
+        Some(t) => t.max(floor),
+        None => {
+            // A clock before the epoch is behind the floor as well.
+            let now = clock
+                .now_nanos()
+                .map(|nanos| nanos.div_euclid(1_000_000_000));
+            if now.is_ok_and(|now| now >= floor) {
+                return Ok(());
+            }
+
+            println!("no saved time, apply time floor");
+            floor
+        }
+    };
+    clock.set_nanos(t.checked_mul(1_000_000_000).ok_or(Error::ImplausibleTime)?)
+}
+
+/// Queries every server once and reports the results
+/// without touching the clock or the persisted time.
+async fn check(
+    config: &Config,
+    key: Option<&Key>,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+) -> Result<()> {
+    let mut reachable = 0;
+    for server in &config.servers {
+        let deadline = Instant::now() + Duration::from_secs(config.request_timeout);
+        match query(config, resolver, nts, server, key, deadline, 0).await {
+            Ok(sample) => {
+                println!(
+                    "{}: reachable, stratum {}, offset {:+.6} s, delay {:.6} s",
+                    server, sample.packet.stratum, sample.offset, sample.delay
+                );
+                reachable += 1;
+            }
+            Err(e) => println!("{}: unreachable: {}", server, e),
+        }
     }
 
-    let timespec = TimeSpec::new(t, 0);
-    nix::time::clock_settime(ClockId::CLOCK_REALTIME, timespec)?;
+    println!("{}/{} servers reachable", reachable, config.servers.len());
 
-    fs::write(LAST_UNIX_PATH, t.to_be_bytes()).await?;
+    if reachable == 0 {
+        return Err(Error::NoReachableServer);
+    }
 
-    println!("set system time");
     Ok(())
 }
 
-async fn resolve_custom_dns(hostname: &str, custom_dns: SocketAddr) -> Result<IpAddr> {
-    let mut cfg = ResolverConfig::new();
+/// Checks the freshly set clock against a different server, warning if
+/// it disagrees by more than `verify_threshold` seconds. If `verify_revert`
+/// is set and the step was larger than that as well, it is undone.
+async fn verify_sync(
+    clock: &mut impl Clock,
+    config: &Config,
+    key: Option<&Key>,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    outcome: &SyncOutcome,
+) {
+    let servers = config.sample_servers(0);
+    for server in servers.iter().filter(|server| **server != outcome.server) {
+        let deadline = Instant::now() + Duration::from_secs(config.request_timeout);
+        let sample = match query(config, resolver, nts, server, key, deadline, 0).await {
+            Ok(sample) => sample,
+            Err(e) => {
+                eprintln!("can't verify sync using {}: {}", server, e);
+                continue;
+            }
+        };
+
+        if sample.offset.abs() <= config.verify_threshold {
+            println!(
+                "verify sync using {}, offset {}",
+                server,
+                format_offset(sample.offset)
+            );
+            return;
+        }
+
+        eprintln!(
+            "{} disagrees with {} by {}, one of them may be wrong",
+            server,
+            outcome.server,
+            format_offset(sample.offset)
+        );
+
+        if config.verify_revert
+            && outcome.action == "step"
+            && outcome.correction.abs() > config.verify_threshold
+        {
+            // The step may include eras and the elapsed time, undo exactly that.
+            match clock.step(-outcome.correction) {
+                Ok(_) => eprintln!("revert step of {:+.3} s", outcome.correction),
+                Err(e) => eprintln!("can't revert step: {}", e),
+            }
+
+            if let Err(e) = sysnow_to_disk().await {
+                eprintln!("can't save system time: {}", e);
+            }
+        }
+
+        return;
+    }
+
+    eprintln!("no other server available to verify sync");
+}
+
+/// Queries the servers other than `synced` until `cross_check` of them
+/// agree with its offset within `cross_check_tolerance` seconds,
+/// returning how many did.
+async fn cross_check(
+    config: &Config,
+    key: Option<&Key>,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    synced: &str,
+    offset: f64,
+) -> Result<usize> {
+    let mut agreeing = 0;
+    let mut queried = 0;
+
+    let servers = config.sample_servers(0);
+    for server in servers.iter().filter(|server| *server != synced) {
+        let deadline = Instant::now() + Duration::from_secs(config.request_timeout);
+        let sample = match query(config, resolver, nts, server, key, deadline, 0).await {
+            Ok(sample) => sample,
+            Err(e) => {
+                eprintln!("can't cross-check {} using {}: {}", synced, server, e);
+                continue;
+            }
+        };
+        queried += 1;
+
+        let difference = sample.offset - offset;
+        if difference.abs() <= config.cross_check_tolerance {
+            agreeing += 1;
+            if agreeing >= config.cross_check {
+                return Ok(agreeing);
+            }
+        } else {
+            eprintln!(
+                "{} disagrees with {} by {}",
+                server,
+                synced,
+                format_offset(difference)
+            );
+        }
+    }
+
+    Err(Error::Disagreement(synced.to_string(), agreeing, queried))
+}
+
+/// Queries all servers other than the one that was just synchronized to
+/// and warns if they consistently disagree with it.
+async fn check_asymmetry(
+    config: &Config,
+    key: Option<&Key>,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    synced: &str,
+) -> Option<f64> {
+    let mut offsets = Vec::new();
+    let servers = config.sample_servers(0);
+    for server in servers.iter().filter(|server| *server != synced) {
+        let deadline = Instant::now() + Duration::from_secs(config.request_timeout);
+        match query(config, resolver, nts, server, key, deadline, 0).await {
+            Ok(sample) => offsets.push(sample.offset),
+            Err(e) => eprintln!("can't query {} for asymmetry check: {}", server, e),
+        }
+    }
+
+    let bias = asymmetry::estimate_bias(&offsets)?;
+    eprintln!(
+        "{} other servers agree on an offset of {:+.3} ms after syncing to {}, \
+        path asymmetry may be degrading accuracy (estimated bias {:+.3} ms)",
+        offsets.len(),
+        bias * 1000.0,
+        synced,
+        -bias * 1000.0
+    );
+
+    Some(-bias)
+}
+
+/// Tries up to `attempts` times within a single tick, cycling through the servers.
+/// Servers are tried in the order of their historical reliability.
+#[allow(clippy::too_many_arguments)]
+async fn sync_with_retries(
+    clock: &mut impl Clock,
+    config: &Config,
+    key: Option<&Key>,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    health: &mut Health,
+    history: &mut History,
+    mode: SyncMode,
+) -> Result<SyncOutcome> {
+    if config.servers.is_empty() {
+        return Err(Error::NoServers);
+    }
+
+    let mut servers = health.order(&config.sample_servers(health.polls()));
+
+    // The reference clock and broadcast servers aren't reordered by their
+    // success rate. Fallbacks get an extra attempt each after all others.
+    let mut fallbacks = Vec::new();
+    if config.refclock.is_some() {
+        if config.refclock_primary {
+            servers.insert(0, refclock::SERVER.to_string());
+        } else {
+            fallbacks.push(refclock::SERVER.to_string());
+        }
+    }
+    if config.broadcast {
+        fallbacks.push(broadcast::SERVER.to_string());
+    }
+    let attempts = config.attempts + fallbacks.len();
+
+    // A slow server mustn't be able to stretch the tick beyond its bound
+    // no matter how the time is split between the attempts.
+    let deadline = Instant::now() + tick_timeout(config, mode.burst, &fallbacks);
+
+    let mut attempt: usize = 0;
+    let mut no_reply = true;
+    loop {
+        let fallback = attempt
+            .checked_sub(config.attempts)
+            .and_then(|i| fallbacks.get(i));
+        let server = fallback.unwrap_or_else(|| &servers[attempt % servers.len()]);
+        attempt += 1;
+
+        let sync = sync_time(
+            clock,
+            config,
+            resolver,
+            nts,
+            server,
+            key,
+            health.polls(),
+            mode,
+        );
+        let result = match tokio::time::timeout_at(deadline, sync).await {
+            Ok(result) => result,
+            Err(e) => Err(e.into()),
+        };
+        if !matches!(result, Err(Error::NoReply(_) | Error::SendFailed(..))) {
+            no_reply = false;
+        }
+
+        match &result {
+            Ok(outcome) => {
+                health.record_success(server, outcome.delay);
+                health.prefer(server.clone());
+            }
+            Err(Error::RateLimited(_, secs)) => health.back_off(server, *secs),
+            Err(Error::Panic(_)) => {}
+            Err(_) => {
+                health.record_failure(server, config.blacklist_after, config.blacklist_cooldown)
+            }
+        }
+
+        history.push(
+            history::Entry {
+                timestamp: now_unix().ok(),
+                server: server.clone(),
+                success: result.is_ok(),
+                offset: result.as_ref().ok().map(|outcome| outcome.offset),
+                delay: result.as_ref().ok().map(|outcome| outcome.delay),
+            },
+            config.history_size,
+        );
+
+        if let Some(path) = &config.event_log {
+            let event = match &result {
+                Ok(outcome) => Event {
+                    timestamp: now_unix().ok(),
+                    server,
+                    success: true,
+                    offset: Some(outcome.offset),
+                    delay: Some(outcome.delay),
+                    stratum: Some(outcome.stratum),
+                    action: outcome.action,
+                    error: None,
+                },
+                Err(e) => Event {
+                    timestamp: now_unix().ok(),
+                    server,
+                    success: false,
+                    offset: None,
+                    delay: None,
+                    stratum: None,
+                    action: "none",
+                    error: Some(e.to_string()),
+                },
+            };
+
+            let result = events::append(path, config.event_log_size, &event).await;
+            APPEND_EVENT.report(
+                format_args!("append to event log {}", path.display()),
+                &result,
+            );
+        }
+
+        let retry_at = Instant::now() + Duration::from_secs(config.retry_delay);
+        match result {
+            Ok(outcome) => return Ok(outcome),
+            Err(e @ Error::Panic(_)) => return Err(e),
+            Err(e) if attempt < attempts && retry_at < deadline => {
+                eprintln!(
+                    "can't synchronize system time using {} (attempt {}/{}): {}",
+                    server, attempt, attempts, e
+                );
+                tokio::time::sleep_until(retry_at).await;
+            }
+            Err(e) => {
+                if no_reply {
+                    eprintln!("no reply from any server, udp port 123 may be filtered");
+                }
+
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Returns how long the attempts of a tick may take in total:
+/// `attempts * (request_timeout + retry_delay)`, with `request_timeout`
+/// replaced by `burst * (request_timeout + burst_interval)` during a burst
+/// and an attempt for each fallback.
+fn tick_timeout(config: &Config, burst: usize, fallbacks: &[String]) -> Duration {
+    let request = if burst > 1 {
+        (burst as u64).saturating_mul(config.request_timeout + config.burst_interval)
+    } else {
+        config.request_timeout
+    };
+
+    let mut secs = (config.attempts as u64).saturating_mul(request + config.retry_delay);
+    for fallback in fallbacks {
+        let timeout = if fallback == broadcast::SERVER {
+            config.broadcast_timeout
+        } else {
+            config.request_timeout
+        };
+        secs = secs.saturating_add(timeout + config.retry_delay);
+    }
+
+    // A huge number of attempts mustn't overflow the deadline.
+    Duration::from_secs(secs.min(u32::MAX.into()))
+}
+
+/// How a tick synchronizes the clock.
+#[derive(Clone, Copy, Debug)]
+struct SyncMode {
+    /// Requests per attempt, see `query_burst`.
+    burst: usize,
+    /// Slew the clock instead of stepping it.
+    slew_only: bool,
+    /// Refuse corrections larger than this many seconds.
+    panic_threshold: Option<f64>,
+    /// Only measure the offset, never adjust the clock.
+    monitor: bool,
+}
+
+#[derive(Clone, Debug)]
+struct SyncOutcome {
+    server: String,
+    /// The address of `server` that produced the sample.
+    address: SocketAddr,
+    /// Seconds the clock was moved by, including era corrections.
+    correction: f64,
+    offset: f64,
+    delay: f64,
+    stratum: u8,
+    reference_id: String,
+    reference_age: f64,
+    /// Poll interval the server asks for in seconds.
+    poll: u64,
+    leap: u8,
+    /// Number of NTP eras added to the server's timestamp.
+    eras: i64,
+    /// How the clock was corrected, e.g. `step`.
+    action: &'static str,
+    /// Whether the build timestamp had to be used
+    /// because no prior time state exists.
+    cold_start: bool,
+    /// Number of other servers that agreed with the offset
+    /// if `cross_check` is enabled.
+    agreeing: Option<usize>,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn sync_time(
+    clock: &mut impl Clock,
+    config: &Config,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    server: &str,
+    key: Option<&Key>,
+    rotation: usize,
+    mode: SyncMode,
+) -> Result<SyncOutcome> {
+    let (last, cold_start) = match last_time_unix().await {
+        Ok(Some(last)) => {
+            NO_TIME_STATE.store(false, Ordering::Relaxed);
+            (Some(last), false)
+        }
+        Ok(None) => {
+            // Saving keeps failing if /data is read-only.
+            if !NO_TIME_STATE.swap(true, Ordering::Relaxed) {
+                println!("no prior time state, using time floor");
+            }
+            (None, true)
+        }
+        Err(e) => {
+            eprintln!("can't read prior time state, using time floor: {}", e);
+            (None, false)
+        }
+    };
+    let floor = time_floor(config, last);
+
+    let sample = query_burst(config, resolver, nts, server, key, rotation, mode.burst).await?;
+
+    let (t, eras) = transmit_unix(sample.packet.transmit_time, floor)?;
+    if eras > 0 {
+        eprintln!(
+            "add {} ntp era(s) to reply from {}, either the 2036 rollover \
+            has happened or the saved time is wrong",
+            eras, server
+        );
+    }
+
+    // A single compromised or broken server shouldn't be able to set the clock.
+    let agreeing = if config.cross_check > 0 {
+        Some(cross_check(config, key, resolver, nts, server, sample.offset).await?)
+    } else {
+        None
+    };
+
+    let outcome = correct(clock, config, server, &sample, t, mode)?;
+
+    // Spare the flash if the saved time wouldn't change or isn't used
+    // as another clock source is in charge.
+    // The clock is still good if /data is read-only, save_time logs the error.
+    let changed = matches!(outcome.action, "step" | "slew") || Some(t) != last;
+    if changed && !mode.monitor {
+        let _ = save_time(t).await;
+    }
+
+    Ok(SyncOutcome {
+        eras,
+        cold_start,
+        agreeing,
+        ..outcome
+    })
+}
+
+/// Corrects the clock according to a sample whose transmit timestamp
+/// is `t` in Unix time and reports what was done.
+fn correct(
+    clock: &mut impl Clock,
+    config: &Config,
+    server: &str,
+    sample: &Sample,
+    t: i64,
+    mode: SyncMode,
+) -> Result<SyncOutcome> {
+    let time = sample.packet.transmit_time;
+
+    // Account for the time that has passed since the server sent its reply.
+    // The transmit timestamp isn't biased like the offset of the sample.
+    let bias = config.static_offset_ms / 1000.0;
+    let elapsed = sample.received.elapsed().as_secs_f64() + sample.delay.max(0.0) / 2.0 + bias;
+    let nanos = t
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| nanos.checked_add(i64::from(time.nanos())))
+        .and_then(|nanos| nanos.checked_add((elapsed * 1e9) as i64))
+        .ok_or(Error::ImplausibleTime)?;
+
+    let delta = (nanos - clock.now_nanos()?) as f64 / 1e9;
+
+    // A huge correction after the clock has been good is more likely
+    // a bad server (or era) than a clock that is actually that wrong.
+    if let Some(threshold) = mode.panic_threshold {
+        if delta.abs() > threshold {
+            return Err(Error::Panic(delta));
+        }
+    }
+
+    // Correcting a negligible offset is pointless churn.
+    let action = if mode.monitor {
+        "monitor"
+    } else if delta.abs() < config.deadband {
+        "none"
+    } else if mode.slew_only {
+        let duration = delta.abs().min(clock::MAX_SLEW) / clock::SLEW_RATE;
+        if delta.abs() > clock::MAX_SLEW || duration > MAX_SLEW_DURATION.as_secs_f64() {
+            eprintln!(
+                "slewing by {:+.3} s takes {:.1} h (at most {} s per poll), \
+                consider stepping the clock manually",
+                delta,
+                duration / 3600.0,
+                clock::MAX_SLEW
+            );
+        }
+
+        clock.slew(delta)?;
+        "slew"
+    } else {
+        clock.set_nanos(nanos)?;
+
+        if delta < -BACKWARD_STEP_WARN {
+            eprintln!("step clock backwards by {:.3} s", -delta);
+        }
+
+        "step"
+    };
+
+    // The kernel can't set CLOCK_TAI directly, it's derived from CLOCK_REALTIME.
+    if config.clock_id == ClockKind::Tai && !mode.monitor {
+        clock.set_tai_offset(config.tai_offset)?;
+    }
+
+    let details = format!(
+        "offset {}, delay {}, server {} ({}), stratum {}",
+        format_offset(sample.offset),
+        format_secs(sample.delay),
+        server,
+        sample.server.ip(),
+        sample.packet.stratum
+    );
+    match action {
+        "none" => println!("leave system time untouched within deadband, {}", details),
+        "monitor" => println!("monitor system time, {}", details),
+        _ => println!("{} system time, {}", action, details),
+    }
+
+    Ok(SyncOutcome {
+        server: server.to_string(),
+        address: sample.server,
+        correction: delta,
+        offset: sample.offset,
+        delay: sample.delay,
+        stratum: sample.packet.stratum,
+        reference_id: sample.packet.reference_id(),
+        reference_age: sample.reference_age,
+        poll: sample.packet.poll_interval(),
+        leap: sample.packet.leap,
+        eras: 0,
+        action,
+        cold_start: false,
+        agreeing: None,
+    })
+}
+
+/// Converts the transmit timestamp of a reply to Unix time in the earliest era
+/// that doesn't result in a time before `floor`.
+/// Also returns the number of eras added.
+fn transmit_unix(time: packet::Timestamp, floor: i64) -> Result<(i64, i64)> {
+    // Some broken middleboxes zero the transmit timestamp. The era correction
+    // would turn it into a time far in the future. The only legitimate
+    // occurrence is the first second of an era which we can afford to skip.
+    if time.sec == 0 {
+        return Err(Error::ZeroTransmitTime);
+    }
+
+    packet::unix_era_correct(packet::ntp_to_unix(time.sec), floor)
+}
+
+/// Queries the server `burst` times `burst_interval` apart,
+/// returning the sample with the lowest delay as it is the least affected
+/// by queueing.
+async fn query_burst(
+    config: &Config,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    server: &str,
+    key: Option<&Key>,
+    rotation: usize,
+    burst: usize,
+) -> Result<Sample> {
+    let mut best: Option<Sample> = None;
+    let mut last_err = Error::NoReachableServer;
+
+    // Waiting for several announcements would take too long.
+    let burst = if server == broadcast::SERVER {
+        1
+    } else {
+        burst
+    };
+
+    for i in 0..burst.max(1) {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_secs(config.burst_interval)).await;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(config.request_timeout);
+        match query(config, resolver, nts, server, key, deadline, rotation).await {
+            Ok(sample) => {
+                best = Some(match best {
+                    Some(best) if best.delay <= sample.delay => best,
+                    _ => sample,
+                })
+            }
+            Err(e) if burst > 1 => {
+                eprintln!(
+                    "can't query {} ({}/{} in burst): {}",
+                    server,
+                    i + 1,
+                    burst,
+                    e
+                );
+                last_err = e;
+            }
+            Err(e) => last_err = e,
+        }
+    }
+
+    best.ok_or(last_err)
+}
+
+/// Queries the addresses the server resolves to until one of them replies,
+/// trying at most `max_addresses` of them.
+/// The address that produced the last good sample is tried first,
+/// without one the addresses of the preferred family are rotated
+/// by `rotation` to spread the load across all of them.
+/// The `refclock` pseudo server is read from the reference clock
+/// and `broadcast` waits for an announcement instead.
+/// The offset is corrected by `static_offset_ms` for all of them.
+async fn query(
+    config: &Config,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    server: &str,
+    key: Option<&Key>,
+    deadline: Instant,
+    rotation: usize,
+) -> Result<Sample> {
+    let mut sample = query_source(config, resolver, nts, server, key, deadline, rotation).await?;
+    sample.offset += config.static_offset_ms / 1000.0;
+
+    Ok(sample)
+}
+
+/// Queries a server or pseudo server without any bias, see `query`.
+async fn query_source(
+    config: &Config,
+    resolver: &mut Resolver,
+    nts: &mut Nts,
+    server: &str,
+    key: Option<&Key>,
+    deadline: Instant,
+    rotation: usize,
+) -> Result<Sample> {
+    if server == refclock::SERVER {
+        let device = config
+            .refclock
+            .as_deref()
+            .ok_or_else(|| Error::InvalidServer(server.to_string()))?;
+        let offset = config.refclock_offset_ms / 1000.0;
+        return tokio::time::timeout_at(deadline, refclock::sample(device, offset)).await?;
+    }
+    if server == broadcast::SERVER {
+        // Announcements are far less frequent than replies.
+        let timeout = Duration::from_secs(config.broadcast_timeout);
+        return tokio::time::timeout(timeout, broadcast::sample(config, key)).await?;
+    }
+
+    let mut session = if config
+        .nts_servers
+        .iter()
+        .any(|nts_server| nts_server == server)
+    {
+        Some(tokio::time::timeout_at(deadline, nts.session(config, resolver, server)).await??)
+    } else {
+        None
+    };
+
+    // The key exchange server may point us to a different NTP server.
+    let (host, port) = match &session {
+        Some(session) => (session.server.clone(), session.port),
+        None => {
+            let (host, port) = split_host_port(server)?;
+            (host.to_string(), port)
+        }
+    };
+
+    // Link-local servers are only reachable through the interface of their zone.
+    let (zoneless, scope_id) = dns::split_zone(&host)?;
+    let host = zoneless.to_string();
+
+    let mut ip_addrs = tokio::time::timeout_at(deadline, resolver.resolve(config, &host)).await??;
+
+    let preferred = ip_addrs
+        .iter()
+        .take_while(|ip_addr| ip_addr.is_ipv4() == ip_addrs[0].is_ipv4())
+        .count();
+
+    // Stick to the address that produced the last good sample.
+    let sticky = resolver.preferred(&host).and_then(|sticky| {
+        ip_addrs[..preferred]
+            .iter()
+            .position(|ip_addr| *ip_addr == sticky)
+    });
+    ip_addrs[..preferred].rotate_left(sticky.unwrap_or(rotation % preferred));
+
+    // NTS sessions can't be shared by concurrent requests.
+    if config.happy_eyeballs && session.is_none() {
+        if let Some(other) = ip_addrs.get(preferred) {
+            let port = port.unwrap_or(NTP_PORT);
+            let first = dns::socket_addr(ip_addrs[0], port, scope_id);
+            let second = dns::socket_addr(*other, port, scope_id);
+
+            return match tokio::time::timeout_at(deadline, race(config, key, first, second)).await {
+                Ok(Ok(sample)) => {
+                    resolver.prefer(&host, sample.server.ip());
+                    Ok(sample)
+                }
+                Ok(Err(e)) => {
+                    resolver.forget_preferred(&host);
+                    Err(e)
+                }
+                Err(_) => {
+                    resolver.forget_preferred(&host);
+                    Err(Error::NoReply(first))
+                }
+            };
+        }
+    }
+
+    // Don't probe every record of a large pool.
+    let mut last_err = Error::NoHostname;
+    for ip_addr in ip_addrs.into_iter().take(config.max_addresses) {
+        let server_resolved = dns::socket_addr(ip_addr, port.unwrap_or(NTP_PORT), scope_id);
+
+        let request = client::request(config, server_resolved, key, session.as_deref_mut());
+        let e = match tokio::time::timeout_at(deadline, request).await {
+            Ok(Ok(sample)) => {
+                resolver.prefer(&host, ip_addr);
+                return Ok(sample);
+            }
+            Ok(Err(e)) => e,
+            Err(_) => {
+                resolver.forget_preferred(&host);
+                return Err(Error::NoReply(server_resolved));
+            }
+        };
+        resolver.forget_preferred(&host);
+
+        // The server may have rotated its keys, invalidating our cookies.
+        if session.is_some() && matches!(e, Error::NtsUnauthenticated | Error::KissOfDeath(..)) {
+            eprintln!("can't query {}: {}, redoing nts-ke", server_resolved, e);
+            nts.forget(server);
+            return Err(e);
+        }
+
+        eprintln!("can't query {}: {}", server_resolved, e);
+        last_err = e;
+    }
+
+    Err(last_err)
+}
+
+/// Queries two addresses (one of each family) concurrently,
+/// returning the first valid reply and cancelling the other request.
+async fn race(
+    config: &Config,
+    key: Option<&Key>,
+    first: SocketAddr,
+    second: SocketAddr,
+) -> Result<Sample> {
+    let first_request = client::request(config, first, key, None);
+    let second_request = client::request(config, second, key, None);
+    tokio::pin!(first_request, second_request);
+
+    let mut first_err = None;
+    let mut second_err = None;
+    loop {
+        tokio::select! {
+            result = &mut first_request, if first_err.is_none() => match result {
+                Ok(sample) => return Ok(sample),
+                Err(e) => {
+                    eprintln!("can't query {}: {}", first, e);
+                    first_err = Some(e);
+                }
+            },
+            result = &mut second_request, if second_err.is_none() => match result {
+                Ok(sample) => return Ok(sample),
+                Err(e) => {
+                    eprintln!("can't query {}: {}", second, e);
+                    second_err = Some(e);
+                }
+            },
+        }
+
+        if let (Some(_), Some(_)) = (&first_err, &second_err) {
+            return Err(first_err.expect("both requests failed"));
+        }
+    }
+}
+
+/// Splits a server string into the host and the optional port.
+/// Accepts `host`, `host:port`, `[addr]`, `[addr]:port` and bare IPv6 addresses.
+fn split_host_port(server: &str) -> Result<(&str, Option<u16>)> {
+    if let Some(bracketed) = server.strip_prefix('[') {
+        let (host, rest) = bracketed
+            .split_once(']')
+            .ok_or_else(|| Error::InvalidServer(server.to_string()))?;
+
+        return match rest.strip_prefix(':') {
+            Some(port) => Ok((host, Some(port.parse()?))),
+            None if rest.is_empty() => Ok((host, None)),
+            None => Err(Error::InvalidServer(server.to_string())),
+        };
+    }
+
+    match server.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Ok((host, Some(port.parse()?))),
+        _ => Ok((server, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    cfg.add_name_server(NameServerConfig::new(custom_dns, Protocol::Udp));
+    use clock::mock::{Adjustment, MockClock};
+    use packet::{Packet, Timestamp};
+
+    const NOW: i64 = 1_700_000_000;
+    const DISCIPLINE: SyncMode = SyncMode {
+        burst: 1,
+        slew_only: false,
+        panic_threshold: None,
+        monitor: false,
+    };
+
+    /// Returns a clock at `NOW` and a sample of a server `secs` ahead of it
+    /// with its transmit timestamp in Unix time.
+    fn sample(secs: f64) -> (MockClock, Sample, i64) {
+        let sample = Sample {
+            server: SocketAddr::from(([192, 0, 2, 1], NTP_PORT)),
+            packet: Packet {
+                stratum: 2,
+                transmit_time: Timestamp::from_unix(NOW, 0),
+                ..Default::default()
+            },
+            offset: secs,
+            delay: 0.0,
+            reference_age: 0.0,
+            received: std::time::Instant::now(),
+        };
 
-    let resolver = AsyncResolver::tokio(cfg, ResolverOpts::default());
-    let response = resolver.lookup_ip(hostname).await?;
+        let clock = MockClock::new((NOW as f64 * 1e9 - secs * 1e9) as i64);
+        (clock, sample, NOW)
+    }
+
+    fn assert_secs(actual: f64, expected: f64) {
+        // The time that passes during the test is accounted for.
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn unix_time_boundaries() {
+        let epoch = SystemTime::UNIX_EPOCH;
+
+        assert_eq!(unix_time(epoch).unwrap(), 0);
+        assert_eq!(unix_time(epoch + Duration::from_millis(999)).unwrap(), 0);
+        assert_eq!(
+            unix_time(epoch + Duration::from_secs(MAX_UNIX as u64)).unwrap(),
+            MAX_UNIX
+        );
+        assert!(matches!(
+            unix_time(epoch - Duration::from_secs(1)),
+            Err(Error::ImplausibleTime)
+        ));
+        assert!(matches!(
+            unix_time(epoch - Duration::from_nanos(1)),
+            Err(Error::ImplausibleTime)
+        ));
+    }
 
-    let ip_addr = response.iter().next().ok_or(Error::NoHostname)?;
-    Ok(ip_addr)
+    #[test]
+    fn zero_transmit_time() {
+        let zeroed = packet::Packet::decode(&[0; packet::PACKET_LEN]).unwrap();
+        assert!(matches!(
+            transmit_unix(zeroed.transmit_time, 0),
+            Err(Error::ZeroTransmitTime)
+        ));
+
+        // Only the seconds matter, the fraction may be garbage.
+        let time = packet::Timestamp { sec: 0, frac: 1 };
+        assert!(matches!(
+            transmit_unix(time, 0),
+            Err(Error::ZeroTransmitTime)
+        ));
+
+        // The first second after the zero is accepted and moved to era 1.
+        let time = packet::Timestamp { sec: 1, frac: 0 };
+        assert_eq!(transmit_unix(time, 1_700_000_000).unwrap(), (2085978497, 1));
+    }
+
+    #[tokio::test]
+    async fn last_interval_round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("rsdsl_ntp-{}.last_interval", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        assert!(load_last_interval(&path).await.unwrap().is_none());
+
+        for interval in [16, 1024, u64::MAX] {
+            save_last_interval(&path, interval).await.unwrap();
+            assert_eq!(load_last_interval(&path).await.unwrap(), Some(interval));
+        }
+
+        fs::write(&path, "1024\n").await.unwrap();
+        assert_eq!(load_last_interval(&path).await.unwrap(), Some(1024));
+
+        fs::write(&path, "-1").await.unwrap();
+        assert!(load_last_interval(&path).await.is_err());
+
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn step_clock() {
+        let (mut clock, sample, t) = sample(-3.5);
+
+        let outcome = correct(&mut clock, &Config::default(), "a", &sample, t, DISCIPLINE).unwrap();
+        assert_eq!(outcome.action, "step");
+        assert_secs(outcome.correction, -3.5);
+
+        match clock.take_adjustments()[..] {
+            [Adjustment::Set(nanos)] => {
+                assert_secs((nanos - NOW * 1_000_000_000) as f64 / 1e9, 0.0)
+            }
+            ref adjustments => panic!("unexpected adjustments {:?}", adjustments),
+        }
+    }
+
+    #[test]
+    fn slew_clock() {
+        let (mut clock, sample, t) = sample(0.25);
+        let mode = SyncMode {
+            slew_only: true,
+            ..DISCIPLINE
+        };
+
+        let outcome = correct(&mut clock, &Config::default(), "a", &sample, t, mode).unwrap();
+        assert_eq!(outcome.action, "slew");
+
+        match clock.take_adjustments()[..] {
+            [Adjustment::Slew(secs)] => assert_secs(secs, 0.25),
+            ref adjustments => panic!("unexpected adjustments {:?}", adjustments),
+        }
+    }
+
+    #[test]
+    fn monitor_clock() {
+        let (mut clock, sample, t) = sample(10.0);
+        let config = Config {
+            clock_id: ClockKind::Tai,
+            ..Default::default()
+        };
+        let mode = SyncMode {
+            monitor: true,
+            ..DISCIPLINE
+        };
+
+        let outcome = correct(&mut clock, &config, "a", &sample, t, mode).unwrap();
+        assert_eq!(outcome.action, "monitor");
+        assert_secs(outcome.correction, 10.0);
+        assert!(clock.take_adjustments().is_empty());
+
+        // CLOCK_TAI follows every correction.
+        correct(&mut clock, &config, "a", &sample, t, DISCIPLINE).unwrap();
+        assert!(matches!(
+            clock.take_adjustments()[..],
+            [Adjustment::Set(_), Adjustment::TaiOffset(37)]
+        ));
+    }
+
+    #[test]
+    fn outcome_fields() {
+        let (mut clock, mut sample, t) = sample(-0.5);
+        sample.packet.ref_id = [192, 0, 2, 123];
+        sample.packet.poll = 10;
+        sample.packet.leap = leap::LEAP_INSERT;
+        sample.delay = 0.04;
+        sample.reference_age = 12.5;
+
+        let outcome = correct(
+            &mut clock,
+            &Config::default(),
+            "ntp.example",
+            &sample,
+            t,
+            DISCIPLINE,
+        )
+        .unwrap();
+
+        assert_eq!(outcome.server, "ntp.example");
+        assert_eq!(outcome.address, sample.server);
+        // Half of the delay has passed since the server replied.
+        assert_secs(outcome.correction, -0.48);
+        assert_eq!(outcome.offset, -0.5);
+        assert_eq!(outcome.delay, 0.04);
+        assert_eq!(outcome.stratum, 2);
+        assert_eq!(outcome.reference_id, "192.0.2.123");
+        assert_eq!(outcome.reference_age, 12.5);
+        assert_eq!(outcome.poll, 1024);
+        assert_eq!(outcome.leap, leap::LEAP_INSERT);
+        assert_eq!(outcome.action, "step");
+
+        // Filled in by sync_time.
+        assert_eq!(outcome.eras, 0);
+        assert!(!outcome.cold_start);
+        assert_eq!(outcome.agreeing, None);
+    }
+
+    #[test]
+    fn deadband() {
+        let config = Config {
+            deadband: 0.01,
+            ..Default::default()
+        };
+        let slew_only = SyncMode {
+            slew_only: true,
+            ..DISCIPLINE
+        };
+
+        for secs in [0.0, 0.009, -0.009] {
+            for mode in [DISCIPLINE, slew_only] {
+                let (mut clock, reply, t) = sample(secs);
+
+                let outcome = correct(&mut clock, &config, "a", &reply, t, mode).unwrap();
+                assert_eq!(outcome.action, "none", "{}", secs);
+                assert_secs(outcome.correction, secs);
+                assert!(clock.take_adjustments().is_empty());
+            }
+        }
+
+        for secs in [0.011, -0.011] {
+            let (mut clock, reply, t) = sample(secs);
+            let outcome = correct(&mut clock, &config, "a", &reply, t, DISCIPLINE).unwrap();
+            assert_eq!(outcome.action, "step", "{}", secs);
+
+            let (mut clock, reply, t) = sample(secs);
+            let outcome = correct(&mut clock, &config, "a", &reply, t, slew_only).unwrap();
+            assert_eq!(outcome.action, "slew", "{}", secs);
+        }
+
+        // Without a deadband even the smallest offset is corrected.
+        let (mut clock, reply, t) = sample(0.000001);
+        let outcome = correct(&mut clock, &Config::default(), "a", &reply, t, DISCIPLINE).unwrap();
+        assert_eq!(outcome.action, "step");
+    }
+
+    #[test]
+    fn tick_bound() {
+        let config = Config::default();
+        assert_eq!(
+            tick_timeout(&config, 1, &[]),
+            Duration::from_secs(3 * (5 + 2))
+        );
+        assert_eq!(
+            tick_timeout(&config, 4, &[]),
+            Duration::from_secs(3 * (4 * (5 + 2) + 2))
+        );
+
+        let fallbacks = [refclock::SERVER.to_string(), broadcast::SERVER.to_string()];
+        assert_eq!(
+            tick_timeout(&config, 1, &fallbacks),
+            Duration::from_secs(3 * (5 + 2) + (5 + 2) + (128 + 2))
+        );
+
+        let config = Config {
+            attempts: usize::MAX,
+            ..Default::default()
+        };
+        assert_eq!(
+            tick_timeout(&config, 1, &[]),
+            Duration::from_secs(u32::MAX.into())
+        );
+    }
+
+    #[test]
+    fn split_host_port_forms() {
+        let split = |server| split_host_port(server).unwrap();
+
+        assert_eq!(split("pool.ntp.org"), ("pool.ntp.org", None));
+        assert_eq!(split("pool.ntp.org:1123"), ("pool.ntp.org", Some(1123)));
+        assert_eq!(split("192.0.2.1"), ("192.0.2.1", None));
+        assert_eq!(split("192.0.2.1:123"), ("192.0.2.1", Some(123)));
+        assert_eq!(split("2001:db8::1"), ("2001:db8::1", None));
+        assert_eq!(split("[2001:db8::1]"), ("2001:db8::1", None));
+        assert_eq!(split("[2001:db8::1]:123"), ("2001:db8::1", Some(123)));
+
+        // Zones are split off later.
+        assert_eq!(split("fe80::1%ppp0"), ("fe80::1%ppp0", None));
+        assert_eq!(split("[fe80::1%ppp0]"), ("fe80::1%ppp0", None));
+        assert_eq!(split("[fe80::1%ppp0]:123"), ("fe80::1%ppp0", Some(123)));
+
+        for invalid in ["[2001:db8::1", "[2001:db8::1]123", "[2001:db8::1]:x"] {
+            assert!(split_host_port(invalid).is_err(), "{}", invalid);
+        }
+        assert!(split_host_port("pool.ntp.org:65536").is_err());
+    }
 }
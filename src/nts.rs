@@ -0,0 +1,436 @@
+use crate::config::Config;
+use crate::dns::Resolver;
+use crate::packet::PACKET_LEN;
+use crate::{split_host_port, Error, Result};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use aes_siv::aead::{Aead, KeyInit, Payload};
+use aes_siv::{Aes128SivAead, Nonce};
+use tokio_rustls::rustls::{self, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+const KE_PORT: u16 = 4460;
+const ALPN: &[u8] = b"ntske/1";
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+
+const PROTOCOL_NTPV4: u16 = 0;
+const AEAD_AES_SIV_CMAC_256: u16 = 15;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+
+/// Number of cookies to keep, missing ones are requested with placeholders.
+const MAX_COOKIES: usize = 8;
+/// Upper bound for the size of a single key exchange record.
+const MAX_RECORD_LEN: usize = 4096;
+/// Upper bound for the number of key exchange records.
+const MAX_RECORDS: usize = 64;
+
+const CRITICAL: u16 = 0x8000;
+const RECORD_END: u16 = 0;
+const RECORD_NEXT_PROTOCOL: u16 = 1;
+const RECORD_ERROR: u16 = 2;
+const RECORD_WARNING: u16 = 3;
+const RECORD_AEAD: u16 = 4;
+const RECORD_NEW_COOKIE: u16 = 5;
+const RECORD_SERVER: u16 = 6;
+const RECORD_PORT: u16 = 7;
+
+const EF_UNIQUE_ID: u16 = 0x0104;
+const EF_COOKIE: u16 = 0x0204;
+const EF_COOKIE_PLACEHOLDER: u16 = 0x0304;
+const EF_AUTHENTICATOR: u16 = 0x0404;
+
+/// Keys and cookies obtained from an NTS-KE server (RFC 8915).
+pub struct Session {
+    c2s: Aes128SivAead,
+    s2c: Aes128SivAead,
+    cookies: Vec<Vec<u8>>,
+    /// NTP server (host and port) the key exchange server pointed us to.
+    pub server: String,
+    pub port: Option<u16>,
+    pending: Vec<[u8; 32]>,
+}
+
+impl Session {
+    /// Appends the unique identifier, a cookie, placeholders for missing
+    /// cookies and the authenticator extension fields to an outgoing packet.
+    pub fn seal(&mut self, packet: &mut Vec<u8>) -> Result<()> {
+        let cookie = self.cookies.pop().ok_or(Error::NtsNoCookies)?;
+
+        let mut uid = [0; 32];
+        getrandom::getrandom(&mut uid)?;
+
+        push_field(packet, EF_UNIQUE_ID, &uid);
+        push_field(packet, EF_COOKIE, &cookie);
+        for _ in self.cookies.len() + 1..MAX_COOKIES {
+            push_field(packet, EF_COOKIE_PLACEHOLDER, &vec![0; cookie.len()]);
+        }
+
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)?;
+
+        let ciphertext = self
+            .c2s
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &[],
+                    aad: packet,
+                },
+            )
+            .map_err(|_| Error::NtsUnauthenticated)?;
+
+        let mut auth = Vec::new();
+        auth.extend_from_slice(&(NONCE_LEN as u16).to_be_bytes());
+        auth.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+        auth.extend_from_slice(&nonce);
+        auth.extend_from_slice(&ciphertext);
+        push_field(packet, EF_AUTHENTICATOR, &auth);
+
+        self.pending.push(uid);
+        Ok(())
+    }
+
+    /// Verifies the authenticator of a reply from `server` to one of the
+    /// sealed requests and stores the new cookies it contains.
+    pub fn open(&mut self, server: SocketAddr, packet: &[u8]) -> Result<()> {
+        let mut uid_matches = false;
+
+        for (offset, field_type, body) in fields(packet, PACKET_LEN)? {
+            match field_type {
+                EF_UNIQUE_ID => uid_matches = self.pending.iter().any(|uid| *uid == body),
+                EF_AUTHENTICATOR if uid_matches => {
+                    let plaintext = self.decrypt(&packet[..offset], body)?;
+
+                    for (_, field_type, body) in fields(&plaintext, 0)? {
+                        if field_type == EF_COOKIE && self.cookies.len() < MAX_COOKIES {
+                            self.cookies.push(body.to_vec());
+                        }
+                    }
+
+                    self.pending.clear();
+                    return Ok(());
+                }
+                EF_AUTHENTICATOR => return Err(Error::Spoofed(server)),
+                _ => {}
+            }
+        }
+
+        Err(Error::NtsUnauthenticated)
+    }
+
+    fn decrypt(&self, aad: &[u8], auth: &[u8]) -> Result<Vec<u8>> {
+        if auth.len() < 4 {
+            return Err(Error::NtsUnauthenticated);
+        }
+
+        let nonce_len = u16::from_be_bytes([auth[0], auth[1]]) as usize;
+        let ciphertext_len = u16::from_be_bytes([auth[2], auth[3]]) as usize;
+        let ciphertext_start = 4 + padded_len(nonce_len);
+        if nonce_len != NONCE_LEN || auth.len() < ciphertext_start + ciphertext_len {
+            return Err(Error::NtsUnauthenticated);
+        }
+
+        self.s2c
+            .decrypt(
+                Nonce::from_slice(&auth[4..4 + nonce_len]),
+                Payload {
+                    msg: &auth[ciphertext_start..ciphertext_start + ciphertext_len],
+                    aad,
+                },
+            )
+            .map_err(|_| Error::NtsUnauthenticated)
+    }
+}
+
+/// NTS sessions by server as configured.
+#[derive(Default)]
+pub struct Nts {
+    sessions: HashMap<String, Session>,
+}
+
+impl Nts {
+    /// Returns the session for an NTS server,
+    /// performing a new key exchange if there is none or it ran out of cookies.
+    pub async fn session(
+        &mut self,
+        config: &Config,
+        resolver: &mut Resolver,
+        server: &str,
+    ) -> Result<&mut Session> {
+        let exhausted = match self.sessions.get(server) {
+            Some(session) => session.cookies.is_empty(),
+            None => true,
+        };
+
+        if exhausted {
+            let session = key_exchange(config, resolver, server).await?;
+            self.sessions.insert(server.to_string(), session);
+        }
+
+        Ok(self
+            .sessions
+            .get_mut(server)
+            .expect("session was just created"))
+    }
+
+    /// Discards the session so that the next request performs a new key exchange,
+    /// e.g. after the server rejected our cookies.
+    pub fn forget(&mut self, server: &str) {
+        self.sessions.remove(server);
+    }
+}
+
+async fn key_exchange(config: &Config, resolver: &mut Resolver, server: &str) -> Result<Session> {
+    let (host, port) = split_host_port(server)?;
+
+    let ip_addr = *resolver
+        .resolve(config, host)
+        .await?
+        .first()
+        .ok_or(Error::NoHostname)?;
+    let ke_server = SocketAddr::new(ip_addr, port.unwrap_or(KE_PORT));
+
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    let mut tls_config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![ALPN.to_vec()];
+
+    let tcp = TcpStream::connect(ke_server).await?;
+    let mut stream = TlsConnector::from(Arc::new(tls_config))
+        .connect(ServerName::try_from(host)?, tcp)
+        .await?;
+
+    let mut request = Vec::new();
+    push_record(
+        &mut request,
+        RECORD_NEXT_PROTOCOL | CRITICAL,
+        &PROTOCOL_NTPV4.to_be_bytes(),
+    );
+    push_record(
+        &mut request,
+        RECORD_AEAD,
+        &AEAD_AES_SIV_CMAC_256.to_be_bytes(),
+    );
+    push_record(&mut request, RECORD_END | CRITICAL, &[]);
+    stream.write_all(&request).await?;
+
+    let mut protocol = None;
+    let mut algorithm = None;
+    let mut cookies = Vec::new();
+    let mut ntp_server = host.to_string();
+    let mut ntp_port = None;
+
+    for _ in 0..MAX_RECORDS {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+
+        let record_type = u16::from_be_bytes([header[0], header[1]]) & !CRITICAL;
+        let critical = header[0] & 0x80 != 0;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(Error::NtsKeyExchange(format!("record of {} bytes", len)));
+        }
+
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await?;
+
+        match record_type {
+            RECORD_END => break,
+            RECORD_NEXT_PROTOCOL => protocol = be_u16(&body),
+            RECORD_ERROR => {
+                let code = be_u16(&body).unwrap_or_default();
+                return Err(Error::NtsKeyExchange(format!("error {}", code)));
+            }
+            RECORD_WARNING => {
+                let code = be_u16(&body).unwrap_or_default();
+                eprintln!("nts-ke warning {} from {}", code, server);
+            }
+            RECORD_AEAD => algorithm = be_u16(&body),
+            RECORD_NEW_COOKIE => cookies.push(body),
+            RECORD_SERVER => ntp_server = String::from_utf8_lossy(&body).into_owned(),
+            RECORD_PORT => ntp_port = be_u16(&body),
+            _ if critical => {
+                return Err(Error::NtsKeyExchange(format!(
+                    "unknown critical record {}",
+                    record_type
+                )))
+            }
+            _ => {}
+        }
+    }
+
+    if protocol != Some(PROTOCOL_NTPV4) || algorithm != Some(AEAD_AES_SIV_CMAC_256) {
+        return Err(Error::NtsKeyExchange(
+            "no common protocol or algorithm".to_string(),
+        ));
+    }
+    if cookies.is_empty() {
+        return Err(Error::NtsNoCookies);
+    }
+
+    let (_, conn) = stream.get_ref();
+    let export = |direction: u8| -> Result<Aes128SivAead> {
+        let mut context = Vec::new();
+        context.extend_from_slice(&PROTOCOL_NTPV4.to_be_bytes());
+        context.extend_from_slice(&AEAD_AES_SIV_CMAC_256.to_be_bytes());
+        context.push(direction);
+
+        let key = conn.export_keying_material([0; KEY_LEN], EXPORTER_LABEL, Some(&context))?;
+        Ok(Aes128SivAead::new_from_slice(&key).expect("key has the correct length"))
+    };
+
+    let session = Session {
+        c2s: export(0)?,
+        s2c: export(1)?,
+        cookies,
+        server: ntp_server,
+        port: ntp_port,
+        pending: Vec::new(),
+    };
+
+    println!(
+        "nts-ke with {} done, {} cookies for {}",
+        ke_server,
+        session.cookies.len(),
+        session.server
+    );
+
+    Ok(session)
+}
+
+fn push_record(buf: &mut Vec<u8>, record_type: u16, body: &[u8]) {
+    buf.extend_from_slice(&record_type.to_be_bytes());
+    buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    buf.extend_from_slice(body);
+}
+
+/// Appends an NTP extension field (RFC 7822), padding the body to a multiple of 4 bytes.
+fn push_field(buf: &mut Vec<u8>, field_type: u16, body: &[u8]) {
+    let len = 4 + padded_len(body.len());
+
+    buf.extend_from_slice(&field_type.to_be_bytes());
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    buf.extend_from_slice(body);
+    buf.resize(buf.len() + padded_len(body.len()) - body.len(), 0);
+}
+
+/// Splits the extension fields starting at `start` into their offset, type and body.
+fn fields(buf: &[u8], start: usize) -> Result<Vec<(usize, u16, &[u8])>> {
+    let mut fields = Vec::new();
+
+    let mut offset = start;
+    while offset + 4 <= buf.len() {
+        let field_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+        let len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        if len < 4 || offset + len > buf.len() {
+            return Err(Error::ShortPacket(buf.len()));
+        }
+
+        fields.push((offset, field_type, &buf[offset + 4..offset + len]));
+        offset += len;
+    }
+
+    Ok(fields)
+}
+
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+fn be_u16(body: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(body.get(..2)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const SERVER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 123));
+
+    /// Returns a session whose server uses the same key as the client
+    /// so that sealed requests can be opened as if they were replies.
+    fn session() -> Session {
+        let key = || Aes128SivAead::new_from_slice(&[7; KEY_LEN]).unwrap();
+
+        Session {
+            c2s: key(),
+            s2c: key(),
+            cookies: vec![vec![1; 16]; 2],
+            server: "ntp.example".to_string(),
+            port: None,
+            pending: Vec::new(),
+        }
+    }
+
+    fn sealed(session: &mut Session) -> Vec<u8> {
+        let mut packet = vec![0; PACKET_LEN];
+        session.seal(&mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn open_reply() {
+        let mut session = session();
+        let reply = sealed(&mut session);
+
+        session.open(SERVER, &reply).unwrap();
+
+        // Replies are only accepted once.
+        assert!(matches!(
+            session.open(SERVER, &reply),
+            Err(Error::Spoofed(SERVER))
+        ));
+    }
+
+    #[test]
+    fn open_unknown_unique_id() {
+        let mut session = session();
+        sealed(&mut session);
+
+        let reply = sealed(&mut self::session());
+        assert!(matches!(
+            session.open(SERVER, &reply),
+            Err(Error::Spoofed(SERVER))
+        ));
+    }
+
+    #[test]
+    fn open_forged() {
+        let mut session = session();
+        let mut reply = sealed(&mut session);
+
+        // The authenticator covers the NTP header.
+        reply[40] ^= 1;
+        assert!(matches!(
+            session.open(SERVER, &reply),
+            Err(Error::NtsUnauthenticated)
+        ));
+
+        // Replies without an authenticator are refused as well.
+        assert!(matches!(
+            session.open(SERVER, &[0; PACKET_LEN]),
+            Err(Error::NtsUnauthenticated)
+        ));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::{Error, Result};
+
+use std::net::Ipv4Addr;
+use std::time::SystemTime;
+
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP epoch (1900-01-01) to the Unix epoch (1970-01-01).
+/// All conversions between NTP and Unix time go through this module.
+const EPOCH_OFFSET: i64 = 2208988800;
+/// Seconds after which the 32-bit NTP seconds field wraps around.
+/// Era 1 starts at 2036-02-07T06:28:16Z (Unix time 2085978496).
+const NTP_ERA_SECONDS: i64 = 1 << 32;
+
+pub const VERSION: u8 = 4;
+pub const MODE_CLIENT: u8 = 3;
+pub const MODE_SERVER: u8 = 4;
+pub const MODE_BROADCAST: u8 = 5;
+
+/// Converts the seconds field of an NTP timestamp to Unix time
+/// assuming era 0 (1900 to 2036).
+pub fn ntp_to_unix(sec: u32) -> i64 {
+    i64::from(sec) - EPOCH_OFFSET
+}
+
+/// Moves a Unix time obtained from an NTP timestamp to the earliest era
+/// that doesn't result in a time before `floor`, resolving the ambiguity
+/// of the 32-bit seconds field. Also returns the number of eras added.
+pub fn unix_era_correct(unix: i64, floor: i64) -> Result<(i64, i64)> {
+    if unix >= floor {
+        return Ok((unix, 0));
+    }
+
+    // Round up to the number of whole eras needed to reach `floor`.
+    let eras = floor
+        .checked_sub(unix)
+        .and_then(|secs| secs.checked_add(NTP_ERA_SECONDS - 1))
+        .ok_or(Error::ImplausibleTime)?
+        / NTP_ERA_SECONDS;
+    let unix = eras
+        .checked_mul(NTP_ERA_SECONDS)
+        .and_then(|secs| unix.checked_add(secs))
+        .ok_or(Error::ImplausibleTime)?;
+
+    Ok((unix, eras))
+}
+
+/// Converts Unix time to the seconds field of an NTP timestamp,
+/// dropping the era.
+pub fn unix_to_ntp(unix: i64) -> u32 {
+    // The remainder always fits.
+    (unix.rem_euclid(NTP_ERA_SECONDS) + EPOCH_OFFSET).rem_euclid(NTP_ERA_SECONDS) as u32
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timestamp {
+    pub sec: u32,
+    pub frac: u32,
+}
+
+impl Timestamp {
+    pub fn now() -> Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|_| Error::ImplausibleTime)?;
+
+        let unix = i64::try_from(now.as_secs()).map_err(|_| Error::ImplausibleTime)?;
+
+        Ok(Self::from_unix(unix, now.subsec_nanos()))
+    }
+
+    /// Converts Unix time to a timestamp, dropping the era.
+    /// `nanos` has to be less than 10^9.
+    pub fn from_unix(unix: i64, nanos: u32) -> Self {
+        Self {
+            sec: unix_to_ntp(unix),
+            // Less than 2^32 because nanos is less than 10^9.
+            frac: ((u64::from(nanos) << 32) / 1_000_000_000) as u32,
+        }
+    }
+
+    /// Returns the fractional part in nanoseconds.
+    pub fn nanos(&self) -> u32 {
+        // Less than 10^9 because frac is less than 2^32.
+        ((u64::from(self.frac) * 1_000_000_000) >> 32) as u32
+    }
+
+    /// Returns `self - other` in seconds. The result is correct across era
+    /// boundaries as long as the timestamps are less than 68 years apart.
+    pub fn diff(&self, other: &Self) -> f64 {
+        // Reinterpreting the wrapped difference as signed is intended.
+        (self.as_u64().wrapping_sub(other.as_u64()) as i64) as f64 / 2_f64.powi(32)
+    }
+
+    fn as_u64(&self) -> u64 {
+        (u64::from(self.sec) << 32) | u64::from(self.frac)
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        Self {
+            sec: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            frac: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        }
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.sec.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.frac.to_be_bytes());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Packet {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub ref_id: [u8; 4],
+    pub ref_time: Timestamp,
+    pub orig_time: Timestamp,
+    pub recv_time: Timestamp,
+    pub transmit_time: Timestamp,
+}
+
+impl Packet {
+    pub fn client(transmit_time: Timestamp) -> Self {
+        Self {
+            version: VERSION,
+            mode: MODE_CLIENT,
+            transmit_time,
+            ..Default::default()
+        }
+    }
+
+    /// Formats the reference ID. Stratum 0 (kiss code) and 1 (reference clock)
+    /// servers use four ASCII characters, others the IPv4 address of their
+    /// upstream server or a hash of its IPv6 address.
+    pub fn reference_id(&self) -> String {
+        if self.stratum <= 1 {
+            self.ref_id
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| {
+                    if c.is_ascii_graphic() {
+                        *c as char
+                    } else {
+                        '?'
+                    }
+                })
+                .collect()
+        } else {
+            Ipv4Addr::from(self.ref_id).to_string()
+        }
+    }
+
+    /// Returns the kiss code if this is a Kiss-o'-Death packet.
+    pub fn kiss_code(&self) -> Option<String> {
+        if self.stratum == 0 {
+            Some(self.reference_id())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the poll interval in seconds, limited to the range
+    /// allowed by RFC 5905 (up to 2^17 seconds).
+    pub fn poll_interval(&self) -> u64 {
+        1 << self.poll.clamp(0, 17)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < PACKET_LEN {
+            return Err(Error::ShortPacket(buf.len()));
+        }
+
+        Ok(Self {
+            leap: buf[0] >> 6,
+            version: (buf[0] >> 3) & 0x07,
+            mode: buf[0] & 0x07,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            root_delay: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            root_dispersion: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            ref_id: [buf[12], buf[13], buf[14], buf[15]],
+            ref_time: Timestamp::decode(&buf[16..24]),
+            orig_time: Timestamp::decode(&buf[24..32]),
+            recv_time: Timestamp::decode(&buf[32..40]),
+            transmit_time: Timestamp::decode(&buf[40..48]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; PACKET_LEN] {
+        let mut buf = [0; PACKET_LEN];
+
+        buf[0] = (self.leap << 6) | ((self.version & 0x07) << 3) | (self.mode & 0x07);
+        buf[1] = self.stratum;
+        buf[2] = self.poll as u8;
+        buf[3] = self.precision as u8;
+        buf[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.ref_id);
+        self.ref_time.encode(&mut buf[16..24]);
+        self.orig_time.encode(&mut buf[24..32]);
+        self.recv_time.encode(&mut buf[32..40]);
+        self.transmit_time.encode(&mut buf[40..48]);
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn era_correct_overflow() {
+        assert!(matches!(
+            unix_era_correct(i64::MIN, i64::MAX),
+            Err(Error::ImplausibleTime)
+        ));
+        assert!(matches!(
+            unix_era_correct(0, i64::MAX),
+            Err(Error::ImplausibleTime)
+        ));
+        assert!(matches!(
+            unix_era_correct(-EPOCH_OFFSET, i64::MAX - NTP_ERA_SECONDS),
+            Err(Error::ImplausibleTime)
+        ));
+        assert_eq!(unix_era_correct(i64::MAX, i64::MAX).unwrap(), (i64::MAX, 0));
+    }
+
+    #[test]
+    fn from_unix_boundaries() {
+        assert_eq!(
+            Timestamp::from_unix(0, 0),
+            Timestamp {
+                sec: EPOCH_OFFSET as u32,
+                frac: 0
+            }
+        );
+        assert_eq!(Timestamp::from_unix(-EPOCH_OFFSET, 0).sec, 0);
+        assert_eq!(Timestamp::from_unix(-EPOCH_OFFSET - 1, 0).sec, u32::MAX);
+        assert_eq!(Timestamp::from_unix(i64::MIN, 0).sec, unix_to_ntp(i64::MIN));
+        assert_eq!(Timestamp::from_unix(i64::MAX, 0).sec, unix_to_ntp(i64::MAX));
+
+        let last = Timestamp::from_unix(0, 999_999_999);
+        assert_eq!(last.sec, EPOCH_OFFSET as u32);
+        assert!(last.frac > u32::MAX - 5);
+        assert!(last.nanos() >= 999_999_998);
+
+        assert_eq!(Timestamp::from_unix(0, 500_000_000).frac, 1 << 31);
+    }
+
+    #[test]
+    fn epochs() {
+        let ntp_epoch = chrono::DateTime::parse_from_rfc3339("1900-01-01T00:00:00Z").unwrap();
+        assert_eq!(ntp_epoch.timestamp(), -EPOCH_OFFSET);
+
+        // The NTP epoch.
+        assert_eq!(ntp_to_unix(0), -EPOCH_OFFSET);
+        assert_eq!(unix_to_ntp(-EPOCH_OFFSET), 0);
+
+        // The Unix epoch.
+        assert_eq!(ntp_to_unix(EPOCH_OFFSET as u32), 0);
+        assert_eq!(unix_to_ntp(0), EPOCH_OFFSET as u32);
+        assert_eq!(unix_to_ntp(-1), EPOCH_OFFSET as u32 - 1);
+        assert_eq!(unix_to_ntp(1), EPOCH_OFFSET as u32 + 1);
+
+        // A floor at the Unix epoch moves the 1900s to era 1.
+        assert_eq!(unix_era_correct(0, 0).unwrap(), (0, 0));
+        assert_eq!(unix_era_correct(-1, 0).unwrap(), (NTP_ERA_SECONDS - 1, 1));
+        assert_eq!(
+            unix_era_correct(ntp_to_unix(0), 0).unwrap(),
+            (NTP_ERA_SECONDS - EPOCH_OFFSET, 1)
+        );
+    }
+
+    /// Unix time of the first second of NTP era 1, 2036-02-07T06:28:16Z.
+    const ERA_1: i64 = 2085978496;
+
+    #[test]
+    fn rollover_2036() {
+        let rollover = chrono::DateTime::parse_from_rfc3339("2036-02-07T06:28:16Z").unwrap();
+        assert_eq!(rollover.timestamp(), ERA_1);
+
+        assert_eq!(ntp_to_unix(u32::MAX), ERA_1 - 1);
+        assert_eq!(unix_to_ntp(ERA_1 - 1), u32::MAX);
+        assert_eq!(unix_to_ntp(ERA_1), 0);
+        assert_eq!(unix_to_ntp(ERA_1 + 1), 1);
+    }
+
+    #[test]
+    fn era_correct_around_rollover() {
+        let floor = ERA_1 - 3600;
+
+        // The last seconds of era 0 are still in era 0.
+        assert_eq!(
+            unix_era_correct(ntp_to_unix(u32::MAX), floor).unwrap(),
+            (ERA_1 - 1, 0)
+        );
+        // Wrapped timestamps after the rollover are moved to era 1.
+        assert_eq!(unix_era_correct(ntp_to_unix(0), floor).unwrap(), (ERA_1, 1));
+        assert_eq!(
+            unix_era_correct(ntp_to_unix(3600), floor).unwrap(),
+            (ERA_1 + 3600, 1)
+        );
+
+        // After the rollover even the end of era 0 is too early.
+        assert_eq!(
+            unix_era_correct(ntp_to_unix(u32::MAX), ERA_1).unwrap(),
+            (ERA_1 - 1 + NTP_ERA_SECONDS, 1)
+        );
+        // A floor in a later era requires several eras.
+        assert_eq!(
+            unix_era_correct(ntp_to_unix(0), ERA_1 + NTP_ERA_SECONDS).unwrap(),
+            (ERA_1 + NTP_ERA_SECONDS, 2)
+        );
+    }
+
+    /// Returns pseudo-random numbers, the same ones on every run.
+    fn numbers() -> impl Iterator<Item = u64> {
+        std::iter::successors(Some(0x2545f4914f6cdd1d_u64), |x| {
+            Some(
+                x.wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407),
+            )
+        })
+        .map(|x| x >> 11)
+        .take(10_000)
+    }
+
+    #[test]
+    fn ntp_round_trip() {
+        let edges = [0, 1, EPOCH_OFFSET as u32, u32::MAX - 1, u32::MAX];
+        for sec in numbers().map(|x| x as u32).chain(edges) {
+            assert_eq!(unix_to_ntp(ntp_to_unix(sec)), sec, "{}", sec);
+        }
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        for x in numbers() {
+            // Any floor up to a few eras after the rollover,
+            // any time less than an era after it.
+            let floor = (x % (4 * NTP_ERA_SECONDS as u64)) as i64 - EPOCH_OFFSET;
+            let unix = floor + (x >> 32) as i64 % NTP_ERA_SECONDS;
+
+            let (corrected, _) = unix_era_correct(ntp_to_unix(unix_to_ntp(unix)), floor).unwrap();
+            assert_eq!(corrected, unix, "floor {}", floor);
+        }
+    }
+
+    #[test]
+    fn timestamp_round_trip() {
+        for x in numbers() {
+            let nanos = (x % 1_000_000_000) as u32;
+            let timestamp = Timestamp::from_unix(x as i64, nanos);
+
+            // The fraction has a resolution of about 0.23 ns.
+            assert!(nanos - timestamp.nanos() <= 1, "{}", nanos);
+
+            let mut buf = [0; 8];
+            timestamp.encode(&mut buf);
+            assert_eq!(Timestamp::decode(&buf), timestamp);
+        }
+    }
+
+    #[test]
+    fn diff_across_era() {
+        let before = Timestamp::from_unix(ERA_1 - 1, 500_000_000);
+        let after = Timestamp::from_unix(ERA_1 + 1, 0);
+        assert_eq!(after.sec, 1);
+
+        assert_eq!(after.diff(&before), 1.5);
+        assert_eq!(before.diff(&after), -1.5);
+
+        // Up to 68 years apart.
+        let far = Timestamp::from_unix(ERA_1 + (1 << 31) - 2, 0);
+        assert_eq!(far.diff(&before), f64::from((1_u32 << 31) - 1) - 0.5);
+        assert_eq!(before.diff(&far), -(f64::from((1_u32 << 31) - 1) - 0.5));
+    }
+}
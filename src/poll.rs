@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+
+/// Number of recent offsets that have to be small to lengthen the interval.
+const HISTORY: usize = 4;
+/// Offsets in seconds below which the clock is considered stable.
+const STABLE_OFFSET: f64 = 0.025;
+/// Offsets in seconds above which the clock is considered to be drifting.
+const UNSTABLE_OFFSET: f64 = 0.1;
+
+/// Adapts the poll interval to the stability of the clock,
+/// doubling it while offsets stay small and halving it when they grow.
+#[derive(Clone, Debug)]
+pub struct AdaptivePoll {
+    offsets: VecDeque<f64>,
+    interval: u64,
+}
+
+impl AdaptivePoll {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            offsets: VecDeque::with_capacity(HISTORY),
+            interval,
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Records the offset measured by a sync and adapts the interval.
+    pub fn update(&mut self, offset: f64, min: u64, max: u64) {
+        if self.offsets.len() == HISTORY {
+            self.offsets.pop_front();
+        }
+        self.offsets.push_back(offset.abs());
+
+        if offset.abs() > UNSTABLE_OFFSET {
+            self.interval = (self.interval / 2).max(min);
+            self.offsets.clear();
+        } else if self.offsets.len() == HISTORY
+            && self.offsets.iter().all(|offset| *offset < STABLE_OFFSET)
+        {
+            self.interval = self.interval.saturating_mul(2).min(max);
+            self.offsets.clear();
+        }
+
+        self.interval = self.interval.clamp(min, max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengthen_when_stable() {
+        let mut poll = AdaptivePoll::new(64);
+
+        for _ in 1..HISTORY {
+            poll.update(0.001, 16, 1024);
+            assert_eq!(poll.interval(), 64);
+        }
+        poll.update(-0.001, 16, 1024);
+        assert_eq!(poll.interval(), 128);
+
+        // A moderate offset delays the next increase.
+        for _ in 1..HISTORY {
+            poll.update(0.001, 16, 1024);
+        }
+        poll.update(0.05, 16, 1024);
+        assert_eq!(poll.interval(), 128);
+    }
+
+    #[test]
+    fn shorten_on_large_offsets() {
+        let mut poll = AdaptivePoll::new(256);
+
+        poll.update(0.2, 16, 1024);
+        assert_eq!(poll.interval(), 128);
+        poll.update(-0.2, 16, 1024);
+        assert_eq!(poll.interval(), 64);
+
+        // The history starts over after a change.
+        for _ in 1..HISTORY {
+            poll.update(0.001, 16, 1024);
+        }
+        assert_eq!(poll.interval(), 64);
+    }
+
+    #[test]
+    fn stay_within_bounds() {
+        let mut poll = AdaptivePoll::new(64);
+        for _ in 0..10 * HISTORY {
+            poll.update(0.001, 16, 256);
+        }
+        assert_eq!(poll.interval(), 256);
+
+        for _ in 0..10 {
+            poll.update(1.0, 16, 256);
+        }
+        assert_eq!(poll.interval(), 16);
+
+        // Bounds changed by a reload apply right away.
+        poll.update(0.05, 32, 128);
+        assert_eq!(poll.interval(), 32);
+        let mut poll = AdaptivePoll::new(1024);
+        poll.update(0.05, 32, 128);
+        assert_eq!(poll.interval(), 128);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::client::Sample;
+use crate::packet::{Packet, Timestamp, MODE_SERVER, VERSION};
+use crate::{Error, Result};
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::Path;
+use std::time::Instant;
+
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+/// Pseudo server name standing for the reference clock
+/// in the server order, health statistics and status.
+pub const SERVER: &str = "refclock";
+
+/// Reads NMEA 0183 sentences from a serial device (e.g. a GPS receiver)
+/// until a valid RMC sentence arrives, returning its time plus `offset`
+/// seconds as a sample of a stratum 1 server without any delay.
+/// The device has to be configured (baud rate, raw mode) beforehand.
+/// PPS isn't supported, the sample is only as accurate
+/// as the receiver's timing of the sentences.
+pub async fn sample(device: &Path, offset: f64) -> Result<Sample> {
+    let mut lines = BufReader::new(File::open(device).await?).split(b'\n');
+
+    while let Some(line) = lines.next_segment().await? {
+        let received = Instant::now();
+        let t4 = Timestamp::now()?;
+
+        // The first line is usually incomplete, the checksum catches that.
+        let Some((unix, nanos)) = parse_rmc(&String::from_utf8_lossy(&line)) else {
+            continue;
+        };
+
+        // Sentences are sent some time after the second they describe,
+        // the offset corrects this delay of the receiver.
+        let nanos = unix * 1_000_000_000 + i64::from(nanos) + (offset * 1e9) as i64;
+        let t3 = Timestamp::from_unix(
+            nanos.div_euclid(1_000_000_000),
+            nanos.rem_euclid(1_000_000_000) as u32,
+        );
+
+        let packet = Packet {
+            version: VERSION,
+            mode: MODE_SERVER,
+            stratum: 1,
+            ref_id: *b"NMEA",
+            ref_time: t3,
+            recv_time: t3,
+            transmit_time: t3,
+            ..Default::default()
+        };
+
+        return Ok(Sample {
+            server: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            packet,
+            offset: t3.diff(&t4),
+            delay: 0.0,
+            reference_age: 0.0,
+            received,
+        });
+    }
+
+    Err(Error::Refclock(format!("{} closed", device.display())))
+}
+
+/// Parses an RMC sentence (`$GPRMC,hhmmss.ss,A,...,ddmmyy,...*hh`)
+/// with a valid fix, returning the Unix time and nanoseconds.
+fn parse_rmc(line: &str) -> Option<(i64, u32)> {
+    let (body, checksum) = line.trim().strip_prefix('$')?.split_once('*')?;
+    if body.bytes().fold(0, |acc, b| acc ^ b) != u8::from_str_radix(checksum, 16).ok()? {
+        return None;
+    }
+
+    // Any talker, e.g. GP (GPS) or GN (multiple systems).
+    let fields: Vec<&str> = body.split(',').collect();
+    if fields.len() < 10 || !fields[0].ends_with("RMC") || fields[2] != "A" {
+        return None;
+    }
+
+    let (time, date) = (fields[1], fields[9]);
+    let number = |s: &str, range: Range<usize>| s.get(range)?.parse::<u32>().ok();
+
+    let date = NaiveDate::from_ymd_opt(
+        2000 + number(date, 4..6)? as i32,
+        number(date, 2..4)?,
+        number(date, 0..2)?,
+    )?;
+    let time = NaiveTime::from_hms_opt(
+        number(time, 0..2)?,
+        number(time, 2..4)?,
+        number(time, 4..6)?,
+    )?;
+
+    let nanos = match time_fraction(fields[1]) {
+        Some(fraction) => (fraction * 1e9) as u32,
+        None => 0,
+    };
+
+    let datetime = DateTime::<Utc>::from_naive_utc_and_offset(date.and_time(time), Utc);
+    Some((datetime.timestamp(), nanos.min(999_999_999)))
+}
+
+/// Returns the fractional seconds of an NMEA time like `123519.25`.
+fn time_fraction(time: &str) -> Option<f64> {
+    let digits = time.get(6..)?.strip_prefix('.')?;
+    format!("0.{}", digits).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RMC: &str = "GPRMC,123519.25,A,4807.038,N,01131.000,E,022.4,084.4,230324,003.1,W";
+
+    fn sentence(body: &str) -> String {
+        let checksum = body.bytes().fold(0, |acc, b| acc ^ b);
+        format!("${}*{:02X}\r\n", body, checksum)
+    }
+
+    #[test]
+    fn parse_valid() {
+        assert_eq!(parse_rmc(&sentence(RMC)), Some((1711197319, 250_000_000)));
+
+        // The talker and the fraction don't matter.
+        let body = RMC.replace("GPRMC", "GNRMC").replace("123519.25", "123519");
+        assert_eq!(parse_rmc(&sentence(&body)), Some((1711197319, 0)));
+    }
+
+    #[test]
+    fn reject_bad_checksum() {
+        let line = sentence(RMC).replace("*", "0*");
+        assert_eq!(parse_rmc(&line), None);
+        assert_eq!(parse_rmc(&format!("${}*zz", RMC)), None);
+        assert_eq!(parse_rmc(&format!("${}", RMC)), None);
+    }
+
+    #[test]
+    fn reject_without_fix() {
+        let body = RMC.replace(",A,", ",V,");
+        assert_eq!(parse_rmc(&sentence(&body)), None);
+
+        let body = RMC.replace("GPRMC", "GPGGA");
+        assert_eq!(parse_rmc(&sentence(&body)), None);
+    }
+
+    #[test]
+    fn reject_truncated() {
+        // The first line read from the device usually starts mid-sentence.
+        let line = sentence(RMC);
+        assert_eq!(parse_rmc(&line[20..]), None);
+
+        let body = &RMC[..RMC.find(",084.4").unwrap()];
+        assert_eq!(parse_rmc(&sentence(body)), None);
+        assert_eq!(parse_rmc(&sentence(&RMC.replace("230324", "2303"))), None);
+        assert_eq!(
+            parse_rmc(&sentence(&RMC.replace("123519.25", "1235"))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn sample_with_offset() {
+        let path = std::env::temp_dir().join(format!("rsdsl_ntp-{}.nmea", std::process::id()));
+
+        let line = sentence(RMC);
+        std::fs::write(&path, format!("{}{}", &line[20..], line)).unwrap();
+        let packet = sample(&path, -0.3).await.unwrap().packet;
+        assert_eq!(packet.stratum, 1);
+        assert_eq!(
+            packet.transmit_time,
+            Timestamp::from_unix(1711197318, 950_000_000)
+        );
+
+        std::fs::write(&path, &line[20..]).unwrap();
+        assert!(matches!(sample(&path, 0.0).await, Err(Error::Refclock(_))));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use crate::now_unix;
+
+use std::time::Duration;
+
+use tokio::time::{Instant, Sleep};
+
+/// Keeps track of when the next poll is due.
+#[derive(Clone, Copy, Debug)]
+pub struct Schedule {
+    period: Duration,
+    next: Instant,
+    /// When the most recent poll was due.
+    last: Instant,
+    /// Offset of the polls after the first one that is yet to be applied.
+    phase: Duration,
+}
+
+impl Schedule {
+    /// Creates a schedule whose first poll is due immediately.
+    /// The later polls are offset by `phase` and keep this offset
+    /// as they're due whole periods later.
+    pub fn new(period: Duration, phase: Duration) -> Self {
+        let now = Instant::now();
+
+        Self {
+            period,
+            next: now,
+            last: now,
+            phase,
+        }
+    }
+
+    /// Returns the time between polls.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Changes the period, making the next poll due one period
+    /// after the most recent one so that the time a sync takes
+    /// doesn't shift the schedule. Returns whether the period was different.
+    pub fn set_period(&mut self, period: Duration) -> bool {
+        if period == self.period {
+            return false;
+        }
+
+        self.period = period;
+        self.next = self.last;
+        self.skip_missed();
+
+        true
+    }
+
+    /// Returns a future that completes when the next poll is due.
+    pub fn wait(&self) -> Sleep {
+        tokio::time::sleep_until(self.next)
+    }
+
+    /// Schedules the poll after the one that is due now.
+    /// Polls that have been missed are skipped.
+    pub fn advance(&mut self) {
+        self.next += std::mem::take(&mut self.phase);
+        self.last = self.next;
+        self.skip_missed();
+    }
+
+    fn skip_missed(&mut self) {
+        let now = Instant::now();
+
+        self.next += self.period;
+        if self.next <= now {
+            self.next = now + self.period;
+        }
+    }
+
+    /// Postpones the next poll.
+    pub fn delay(&mut self, delay: Duration) {
+        self.next += delay;
+    }
+
+    /// Makes the next poll due after `delay` at the latest.
+    pub fn retry_in(&mut self, delay: Duration) {
+        self.next = self.next.min(Instant::now() + delay);
+    }
+
+    /// Makes the next poll due immediately.
+    pub fn poll_now(&mut self) {
+        self.next = Instant::now();
+    }
+
+    /// Returns the time remaining until the next poll is due.
+    pub fn remaining(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+
+    /// Returns the Unix time the next poll is due at.
+    pub fn next_unix(&self) -> Option<i64> {
+        now_unix()
+            .ok()?
+            .checked_add_unsigned(self.remaining().as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(64);
+    const PHASE: Duration = Duration::from_secs(5);
+    const SYNC: Duration = Duration::from_secs(3);
+
+    #[tokio::test(start_paused = true)]
+    async fn stable_phase() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(PERIOD, PHASE);
+
+        // The first poll is immediate, the phase only applies to later ones.
+        schedule.wait().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        schedule.advance();
+
+        // The time a sync takes doesn't shift the schedule.
+        for poll in 1..=4 {
+            tokio::time::sleep(SYNC).await;
+            schedule.wait().await;
+            assert_eq!(start.elapsed(), PHASE + poll * PERIOD);
+            schedule.advance();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_period_keeps_phase() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(PERIOD, PHASE);
+
+        schedule.wait().await;
+        schedule.advance();
+        schedule.wait().await;
+        schedule.advance();
+        tokio::time::sleep(SYNC).await;
+
+        // The next poll is one new period after the most recent one.
+        assert!(schedule.set_period(2 * PERIOD));
+        assert!(!schedule.set_period(2 * PERIOD));
+        schedule.wait().await;
+        assert_eq!(start.elapsed(), PHASE + 3 * PERIOD);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_missed_polls() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(PERIOD, Duration::ZERO);
+
+        schedule.wait().await;
+        schedule.advance();
+
+        // A late poll stays on the schedule.
+        tokio::time::sleep(PERIOD + SYNC).await;
+        schedule.wait().await;
+        schedule.advance();
+        assert_eq!(schedule.remaining(), PERIOD - SYNC);
+
+        // Polls missed entirely, e.g. during suspend, don't cause a burst.
+        tokio::time::sleep(3 * PERIOD).await;
+        schedule.wait().await;
+        schedule.advance();
+        assert_eq!(schedule.remaining(), PERIOD);
+        assert_eq!(start.elapsed(), 4 * PERIOD + SYNC);
+    }
+}
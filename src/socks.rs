@@ -0,0 +1,189 @@
+use crate::{Error, Result};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const COMMAND_UDP_ASSOCIATE: u8 = 3;
+const REPLY_SUCCEEDED: u8 = 0;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// Asks a SOCKS5 proxy (RFC 1928) without authentication to relay UDP datagrams.
+/// Returns the control connection, which has to be kept open
+/// for as long as the association is used, and the relay address.
+pub async fn associate(proxy: SocketAddr) -> Result<(TcpStream, SocketAddr)> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    stream.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await?;
+
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [VERSION, METHOD_NO_AUTH] {
+        return Err(Error::Socks(format!("{} requires authentication", proxy)));
+    }
+
+    // The source address of our datagrams isn't known in advance.
+    let unspecified = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+
+    let mut request = vec![VERSION, COMMAND_UDP_ASSOCIATE, 0];
+    push_addr(&mut request, unspecified);
+    stream.write_all(&request).await?;
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION || header[1] != REPLY_SUCCEEDED {
+        return Err(Error::Socks(format!(
+            "{} refuses udp associate (reply {})",
+            proxy, header[1]
+        )));
+    }
+
+    let ip_addr: IpAddr = match header[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).into()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).into()
+        }
+        atyp => return Err(Error::Socks(format!("unsupported address type {}", atyp))),
+    };
+    let port = stream.read_u16().await?;
+
+    // Many proxies reply with an unspecified address meaning their own.
+    let relay = if ip_addr.is_unspecified() {
+        SocketAddr::new(proxy.ip(), port)
+    } else {
+        SocketAddr::new(ip_addr, port)
+    };
+
+    Ok((stream, relay))
+}
+
+/// Prepends the UDP request header addressing `target` to a datagram.
+pub fn encapsulate(target: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0, 0, 0];
+    push_addr(&mut datagram, target);
+    datagram.extend_from_slice(payload);
+
+    datagram
+}
+
+/// Strips the UDP request header from a relayed datagram,
+/// rejecting fragments and datagrams that don't originate from `source`.
+pub fn decapsulate(datagram: &[u8], source: SocketAddr) -> Result<&[u8]> {
+    let short = || Error::ShortPacket(datagram.len());
+
+    if datagram.len() < 4 {
+        return Err(short());
+    }
+    if datagram[2] != 0 {
+        return Err(Error::Socks("fragmented datagram".to_string()));
+    }
+
+    let (ip_addr, rest): (IpAddr, &[u8]) = match datagram[3] {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = datagram.get(4..8).ok_or_else(short)?.try_into()?;
+            (Ipv4Addr::from(octets).into(), &datagram[8..])
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = datagram.get(4..20).ok_or_else(short)?.try_into()?;
+            (Ipv6Addr::from(octets).into(), &datagram[20..])
+        }
+        atyp => return Err(Error::Socks(format!("unsupported address type {}", atyp))),
+    };
+
+    let port = u16::from_be_bytes(rest.get(..2).ok_or_else(short)?.try_into()?);
+    if SocketAddr::new(ip_addr, port) != source {
+        return Err(Error::Socks(format!(
+            "datagram from unexpected address {}",
+            SocketAddr::new(ip_addr, port)
+        )));
+    }
+
+    Ok(&rest[2..])
+}
+
+fn push_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip_addr) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip_addr.octets());
+        }
+        IpAddr::V6(ip_addr) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip_addr.octets());
+        }
+    }
+
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let targets = [
+            SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 123),
+            SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 123),
+        ];
+
+        for target in targets {
+            let datagram = encapsulate(target, b"payload");
+            assert_eq!(&datagram[..3], [0, 0, 0]);
+            assert_eq!(decapsulate(&datagram, target).unwrap(), b"payload");
+        }
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let source = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 123);
+        let datagram = encapsulate(source, b"payload");
+
+        let mut fragment = datagram.clone();
+        fragment[2] = 1;
+        assert!(matches!(
+            decapsulate(&fragment, source),
+            Err(Error::Socks(_))
+        ));
+
+        let mut domain = datagram.clone();
+        domain[3] = 3;
+        assert!(matches!(decapsulate(&domain, source), Err(Error::Socks(_))));
+
+        // The address type announces more bytes than there are.
+        for len in [3, 7, 9] {
+            assert!(matches!(
+                decapsulate(&datagram[..len], source),
+                Err(Error::ShortPacket(n)) if n == len
+            ));
+        }
+        let mut ipv6 = datagram.clone();
+        ipv6[3] = ATYP_IPV6;
+        assert!(matches!(
+            decapsulate(&ipv6[..12], source),
+            Err(Error::ShortPacket(12))
+        ));
+
+        let other = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 2).into(), 123);
+        assert!(matches!(
+            decapsulate(&datagram, other),
+            Err(Error::Socks(_))
+        ));
+        let other = SocketAddr::new(source.ip(), 124);
+        assert!(matches!(
+            decapsulate(&datagram, other),
+            Err(Error::Socks(_))
+        ));
+    }
+}
@@ -0,0 +1,362 @@
+use crate::config::Config;
+use crate::health::ServerStats;
+use crate::history::Entry;
+use crate::{format_offset, now_unix, Error, Result};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+
+use serde::Serialize;
+
+const STATUS_PATH: &str = "/tmp/ntp.status";
+
+/// Maximum length of a command sent to the status socket.
+const COMMAND_LEN: usize = 64;
+/// Clients that don't send a command within this time get the status.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Status {
+    /// Whether the first sync has completed.
+    pub synced: bool,
+    /// Whether a sync is in progress.
+    pub syncing: bool,
+    pub last_sync: Option<i64>,
+    /// Unix timestamp the next poll is due at.
+    pub next_poll: Option<i64>,
+    /// Seconds remaining until the next poll.
+    pub next_poll_in: Option<i64>,
+    /// Current poll interval in seconds.
+    pub poll_interval: u64,
+    /// Whether the clock is currently kept synchronized, i.e. the last sync
+    /// happened within two poll intervals and no attempt failed since.
+    pub disciplined: bool,
+    pub server: Option<String>,
+    /// The address of `server` that produced the last sample.
+    pub address: Option<SocketAddr>,
+    pub offset: Option<f64>,
+    /// Round-trip delay of the last sync in seconds.
+    pub delay: Option<f64>,
+    /// How the clock was corrected by the last sync.
+    pub action: Option<&'static str>,
+    pub stratum: Option<u8>,
+    pub reference_id: Option<String>,
+    /// Seconds since the server last synchronized its own clock.
+    pub reference_age: Option<f64>,
+    /// Estimated error of the last sync due to path asymmetry in seconds.
+    pub asymmetry_bias: Option<f64>,
+    pub consecutive_failures: u32,
+    /// The error of the last attempt if it failed.
+    pub last_error: Option<String>,
+    /// Unix start and end time of the scheduled or running leap smear.
+    pub leap_smear: Option<(i64, i64)>,
+    /// Number of NTP eras added to server timestamps since startup.
+    pub era_rollovers: u64,
+    pub cold_start: bool,
+    /// Number of steps that moved the clock backwards by more than 1 ms.
+    pub backward_steps: u64,
+    /// Whether the last correction was refused for exceeding the panic threshold.
+    pub panic: bool,
+    /// Number of corrections refused for exceeding the panic threshold.
+    pub panics: u64,
+    /// Number of other servers that agreed with the last sync.
+    pub agreeing: Option<usize>,
+    /// Number of syncs refused because too few other servers agreed.
+    pub disagreements: u64,
+    pub servers: BTreeMap<String, ServerStats>,
+    /// The most recent sync attempts, oldest first.
+    pub history: VecDeque<Entry>,
+    /// The configuration in effect, including defaults.
+    pub config: Option<Config>,
+}
+
+impl Status {
+    pub async fn write(&mut self) -> Result<()> {
+        self.update_derived();
+
+        fs::write(STATUS_PATH, serde_json::to_vec_pretty(self)?).await?;
+        Ok(())
+    }
+
+    /// Returns a one-line summary for the service manager.
+    pub fn summary(&self) -> String {
+        let next_poll = match self.next_poll_in {
+            Some(secs) => format!(", next poll in {}s", secs),
+            None => String::new(),
+        };
+
+        match (&self.last_error, &self.server, self.offset) {
+            (Some(e), _, _) => format!(
+                "sync failing: {}, {} consecutive failures{}",
+                e, self.consecutive_failures, next_poll
+            ),
+            (None, Some(server), Some(offset)) => format!(
+                "synced to {}, offset {}{}",
+                server,
+                format_offset(offset),
+                next_poll
+            ),
+            _ => format!("waiting for the first sync{}", next_poll),
+        }
+    }
+
+    /// Updates the fields that depend on the current time.
+    fn update_derived(&mut self) {
+        let now = now_unix().unwrap_or_default();
+        self.next_poll_in = self.next_poll.map(|next| next.saturating_sub(now).max(0));
+
+        let max_age = 2 * i64::try_from(self.poll_interval).unwrap_or(i64::MAX / 2);
+        self.disciplined = self.consecutive_failures == 0
+            && self
+                .last_sync
+                .is_some_and(|last_sync| now.saturating_sub(last_sync) <= max_age);
+    }
+}
+
+/// Succeeds if the status written by the running daemon
+/// reports a sync within the last `max_age` seconds.
+pub async fn check_health(max_age: u64) -> Result<()> {
+    let status: serde_json::Value = serde_json::from_slice(&fs::read(STATUS_PATH).await?)?;
+
+    let last_sync = status["last_sync"].as_i64().ok_or(Error::NotSynced)?;
+    let age = now_unix()?.saturating_sub(last_sync);
+    if age > i64::try_from(max_age)? {
+        return Err(Error::StaleSync(age));
+    }
+
+    println!("last sync {}s ago", age);
+    Ok(())
+}
+
+/// Listens on a Unix socket, replying to every connection
+/// with a JSON snapshot of the status. A client may send `sync`
+/// to request an immediate poll instead, at most once per `sync_interval`.
+pub async fn serve(
+    path: &Path,
+    status: Arc<Mutex<Status>>,
+    resync: Arc<Notify>,
+    sync_interval: Duration,
+) -> Result<()> {
+    match fs::remove_file(path).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let last_sync = Arc::new(Mutex::new(None));
+
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let status = status.clone();
+        let resync = resync.clone();
+        let last_sync = last_sync.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, status, resync, last_sync, sync_interval).await {
+                eprintln!("can't serve status client: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut stream: UnixStream,
+    status: Arc<Mutex<Status>>,
+    resync: Arc<Notify>,
+    last_sync: Arc<Mutex<Option<Instant>>>,
+    sync_interval: Duration,
+) -> Result<()> {
+    let mut buf = [0; COMMAND_LEN];
+    let n = match tokio::time::timeout(COMMAND_TIMEOUT, stream.read(&mut buf)).await {
+        Ok(n) => n?,
+        Err(_) => 0,
+    };
+
+    let reply = match String::from_utf8_lossy(&buf[..n]).trim() {
+        "sync" => {
+            let mut last_sync = last_sync.lock().unwrap();
+            let wait = last_sync
+                .map(|last| sync_interval.saturating_sub(last.elapsed()))
+                .unwrap_or_default();
+
+            if status.lock().unwrap().syncing {
+                println!("ignore sync request, already syncing");
+                b"already syncing\n".to_vec()
+            } else if !wait.is_zero() {
+                println!("ignore sync request, rate limited");
+                format!("error: rate limited, retry in {}s\n", wait.as_secs() + 1).into_bytes()
+            } else {
+                println!("sync requested via status socket");
+                *last_sync = Some(Instant::now());
+                resync.notify_one();
+                b"ok\n".to_vec()
+            }
+        }
+        "" | "status" => {
+            let mut status = status.lock().unwrap();
+            status.update_derived();
+            serde_json::to_vec(&*status)?
+        }
+        command => {
+            eprintln!("unknown status socket command {:?}", command);
+            b"error: unknown command\n".to_vec()
+        }
+    };
+
+    stream.write_all(&reply).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shared state of the status socket.
+    struct Socket {
+        status: Arc<Mutex<Status>>,
+        resync: Arc<Notify>,
+        last_sync: Arc<Mutex<Option<Instant>>>,
+        sync_interval: Duration,
+    }
+
+    impl Socket {
+        fn new(sync_interval: Duration) -> Self {
+            Self {
+                status: Arc::default(),
+                resync: Arc::new(Notify::new()),
+                last_sync: Arc::default(),
+                sync_interval,
+            }
+        }
+
+        /// Sends a command as a client would, returning the reply.
+        async fn send(&self, command: &[u8]) -> String {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            client.write_all(command).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            handle(
+                server,
+                self.status.clone(),
+                self.resync.clone(),
+                self.last_sync.clone(),
+                self.sync_interval,
+            )
+            .await
+            .unwrap();
+
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).await.unwrap();
+            reply
+        }
+
+        /// Reports whether a sync was requested since the last call.
+        async fn resync_requested(&self) -> bool {
+            tokio::time::timeout(Duration::from_millis(10), self.resync.notified())
+                .await
+                .is_ok()
+        }
+    }
+
+    #[tokio::test]
+    async fn ignore_sync_while_syncing() {
+        let socket = Socket::new(Duration::ZERO);
+
+        socket.status.lock().unwrap().syncing = true;
+        assert_eq!(socket.send(b"sync").await, "already syncing\n");
+        assert!(!socket.resync_requested().await);
+
+        socket.status.lock().unwrap().syncing = false;
+        assert_eq!(socket.send(b"sync").await, "ok\n");
+        assert!(socket.resync_requested().await);
+    }
+
+    #[tokio::test]
+    async fn coalesce_concurrent_syncs() {
+        let socket = Arc::new(Socket::new(Duration::ZERO));
+
+        let clients: Vec<_> = (0..8)
+            .map(|_| {
+                let socket = socket.clone();
+                tokio::spawn(async move { socket.send(b"sync\n").await })
+            })
+            .collect();
+        for client in clients {
+            assert_eq!(client.await.unwrap(), "ok\n");
+        }
+
+        // All requests that arrived before the sync started result in one sync.
+        assert!(socket.resync_requested().await);
+        assert!(!socket.resync_requested().await);
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let socket = Socket::new(Duration::from_secs(10));
+
+        assert_eq!(socket.send(b"sync").await, "ok\n");
+        assert!(socket.resync_requested().await);
+
+        assert_eq!(
+            socket.send(b"sync").await,
+            "error: rate limited, retry in 10s\n"
+        );
+        assert!(!socket.resync_requested().await);
+
+        // Rejected requests don't extend the limit.
+        *socket.last_sync.lock().unwrap() = Instant::now().checked_sub(Duration::from_secs(10));
+        assert_eq!(socket.send(b"sync").await, "ok\n");
+        assert!(socket.resync_requested().await);
+    }
+
+    #[tokio::test]
+    async fn unknown_command() {
+        let socket = Socket::new(Duration::ZERO);
+
+        assert_eq!(socket.send(b"reboot\n").await, "error: unknown command\n");
+        assert_eq!(socket.send(b"SYNC").await, "error: unknown command\n");
+
+        let padded = [b"sync".as_slice(), &[b' '; COMMAND_LEN - 4]].concat();
+        assert_eq!(socket.send(&padded).await, "ok\n");
+        assert!(socket.resync_requested().await);
+
+        // Only the first bytes are read from longer commands,
+        // the rest is discarded with the connection.
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let long = [&[b'x'; COMMAND_LEN], b"sync".as_slice()].concat();
+        client.write_all(&long).await.unwrap();
+
+        handle(
+            server,
+            socket.status.clone(),
+            socket.resync.clone(),
+            socket.last_sync.clone(),
+            socket.sync_interval,
+        )
+        .await
+        .unwrap();
+        assert!(!socket.resync_requested().await);
+    }
+
+    #[tokio::test]
+    async fn status_by_default() {
+        let socket = Socket::new(Duration::ZERO);
+        socket.status.lock().unwrap().consecutive_failures = 2;
+
+        for command in [b"".as_slice(), b"status\n"] {
+            let status: serde_json::Value =
+                serde_json::from_str(&socket.send(command).await).unwrap();
+            assert_eq!(status["consecutive_failures"], 2);
+        }
+    }
+}
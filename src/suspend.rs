@@ -0,0 +1,42 @@
+use crate::Result;
+
+use std::time::Duration;
+
+use nix::time::ClockId;
+
+/// Time the system has to be suspended for to count as a resume.
+const THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Detects system suspends by comparing `CLOCK_BOOTTIME`,
+/// which includes the time spent suspended, to `CLOCK_MONOTONIC`,
+/// which doesn't.
+#[derive(Clone, Copy, Debug)]
+pub struct SuspendDetector {
+    suspended: Duration,
+}
+
+impl SuspendDetector {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            suspended: suspended()?,
+        })
+    }
+
+    /// Returns how long the system has been suspended for
+    /// since the last check if that is significant.
+    pub fn check(&mut self) -> Result<Option<Duration>> {
+        let suspended = suspended()?;
+        let slept = suspended.saturating_sub(self.suspended);
+
+        self.suspended = suspended;
+        Ok(Some(slept).filter(|slept| *slept >= THRESHOLD))
+    }
+}
+
+/// Returns the total time the system has been suspended for since boot.
+fn suspended() -> Result<Duration> {
+    let boottime = Duration::from(nix::time::clock_gettime(ClockId::CLOCK_BOOTTIME)?);
+    let monotonic = Duration::from(nix::time::clock_gettime(ClockId::CLOCK_MONOTONIC)?);
+
+    Ok(boottime.saturating_sub(monotonic))
+}
@@ -0,0 +1,22 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Sends a state update (e.g. `STATUS=...`) to the service manager
+/// if the daemon is run by systemd with `NOTIFY_SOCKET` set.
+/// Failures are logged and otherwise ignored.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let result = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    }
+    .and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+
+    if let Err(e) = result {
+        eprintln!("can't notify systemd: {}", e);
+    }
+}